@@ -0,0 +1,228 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Color helpers, all color matching is done in CIELAB so "nearest" means nearest to the eye rather than nearest in RGB
+
+use rand::Rng;
+
+const WHITE: [f32; 3] = [0.95047, 1.0, 1.08883]; // D65
+const EPSILON: f32 = 6.0 / 29.0;
+
+/// A color in CIELAB (D65 white point)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Lab {
+    pub l: f32,
+    pub a: f32,
+    pub b: f32,
+}
+
+fn srgb_to_linear(c: u8) -> f32 {
+    let c = c as f32 / 255.0;
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+fn linear_to_srgb(c: f32) -> u8 {
+    let c = if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 };
+    (c * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+fn lab_f(t: f32) -> f32 {
+    if t > EPSILON.powi(3) { t.cbrt() } else { t / (3.0 * EPSILON * EPSILON) + 4.0 / 29.0 }
+}
+
+fn lab_f_inv(t: f32) -> f32 {
+    if t > EPSILON { t.powi(3) } else { 3.0 * EPSILON * EPSILON * (t - 4.0 / 29.0) }
+}
+
+pub fn rgb_to_lab(rgb: [u8; 3]) -> Lab {
+    let [r, g, b] = rgb.map(srgb_to_linear);
+    let x = 0.4124564 * r + 0.3575761 * g + 0.1804375 * b;
+    let y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+    let z = 0.0193339 * r + 0.119192 * g + 0.9503041 * b;
+    let (fx, fy, fz) = (lab_f(x / WHITE[0]), lab_f(y / WHITE[1]), lab_f(z / WHITE[2]));
+
+    Lab {
+        l: 116.0 * fy - 16.0,
+        a: 500.0 * (fx - fy),
+        b: 200.0 * (fy - fz),
+    }
+}
+
+/// Out of gamut colors are clamped per channel
+pub fn lab_to_rgb(lab: Lab) -> [u8; 3] {
+    let fy = (lab.l + 16.0) / 116.0;
+    let x = WHITE[0] * lab_f_inv(fy + lab.a / 500.0);
+    let y = WHITE[1] * lab_f_inv(fy);
+    let z = WHITE[2] * lab_f_inv(fy - lab.b / 200.0);
+
+    [
+        linear_to_srgb(3.2404542 * x - 1.5371385 * y - 0.4985314 * z),
+        linear_to_srgb(-0.969266 * x + 1.8760108 * y + 0.041556 * z),
+        linear_to_srgb(0.0556434 * x - 0.2040259 * y + 1.0572252 * z),
+    ]
+}
+
+/// CIE76 ΔE, around 2.3 is a just noticeable difference
+pub fn delta_e(a: Lab, b: Lab) -> f32 {
+    ((a.l - b.l).powi(2) + (a.a - b.a).powi(2) + (a.b - b.b).powi(2)).sqrt()
+}
+
+/// The palette color perceptually closest to target, or target itself if the palette is empty
+pub fn nearest(target: [u8; 3], palette: &[[u8; 3]]) -> [u8; 3] {
+    let target_lab = rgb_to_lab(target);
+    palette.iter()
+        .copied()
+        .min_by(|a, b| delta_e(target_lab, rgb_to_lab(*a)).total_cmp(&delta_e(target_lab, rgb_to_lab(*b))))
+        .unwrap_or(target)
+}
+
+/// Maps 0.0-1.0 from black through purple, red and orange to pale yellow, roughly the inferno colormap so equal steps look about equally far apart
+pub fn heat(t: f32) -> [u8; 3] {
+    const STOPS: [[f32; 3]; 5] = [[0.0, 0.0, 4.0], [87.0, 16.0, 110.0], [188.0, 55.0, 84.0], [249.0, 142.0, 9.0], [252.0, 255.0, 164.0]];
+    let t = t.clamp(0.0, 1.0) * (STOPS.len() - 1) as f32;
+    let (i, f) = ((t as usize).min(STOPS.len() - 2), t - (t as usize).min(STOPS.len() - 2) as f32);
+    [0, 1, 2].map(|c| (STOPS[i][c] + (STOPS[i + 1][c] - STOPS[i][c]) * f).round() as u8)
+}
+
+/// Parses `#RRGGBB` or `RRGGBB`
+pub fn parse_hex(s: &str) -> Result<[u8; 3], String> {
+    let hex = s.trim().trim_start_matches('#');
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(format!("{s} is not a color, expected #RRGGBB"));
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| format!("{s} is not a color, expected #RRGGBB"));
+
+    Ok([channel(0)?, channel(2)?, channel(4)?])
+}
+
+/// The center of the biggest k-means cluster, so the color most of the image is close to. None if there are no pixels
+pub fn dominant(pixels: &[[u8; 3]], rng: &mut impl Rng) -> Option<[u8; 3]> {
+    let centers = quantize(pixels, 6, rng);
+    let labs: Vec<Lab> = centers.iter().map(|c| rgb_to_lab(*c)).collect();
+    let mut counts = vec![0u32; centers.len()];
+    for p in pixels {
+        let lab = rgb_to_lab(*p);
+        if let Some(closest) = (0..labs.len()).min_by(|a, b| delta_e(lab, labs[*a]).total_cmp(&delta_e(lab, labs[*b]))) {
+            counts[closest] += 1;
+        }
+    }
+    centers.into_iter().zip(counts).max_by_key(|(_, count)| *count).map(|(c, _)| c)
+}
+
+/// k-means in CIELAB, returns at most n colors (fewer if the image doesn't have that many distinct colors)
+pub fn quantize(pixels: &[[u8; 3]], n: usize, rng: &mut impl Rng) -> Vec<[u8; 3]> {
+    if pixels.is_empty() || n == 0 {
+        return vec![];
+    }
+    let samples: Vec<Lab> = pixels.iter().map(|p| rgb_to_lab(*p)).collect();
+
+    // k-means++ seeding, spreads the starting centers out so dark/rare colors still get one
+    let mut centers = vec![samples[rng.random_range(0..samples.len())]];
+    while centers.len() < n {
+        let dists: Vec<f32> = samples.iter().map(|s| centers.iter().map(|c| delta_e(*s, *c).powi(2)).fold(f32::MAX, f32::min)).collect();
+        let total: f32 = dists.iter().sum();
+        if total <= 0.0 {
+            break;
+        }
+        let mut pick = rng.random_range(0.0..total);
+        let idx = dists.iter().position(|d| { pick -= d; pick <= 0.0 }).unwrap_or(dists.len() - 1);
+        centers.push(samples[idx]);
+    }
+
+    for _ in 0..16 {
+        let mut sums = vec![(0.0f32, 0.0f32, 0.0f32, 0u32); centers.len()];
+        for s in &samples {
+            let closest = (0..centers.len()).min_by(|a, b| delta_e(*s, centers[*a]).total_cmp(&delta_e(*s, centers[*b]))).unwrap();
+            let sum = &mut sums[closest];
+            sum.0 += s.l;
+            sum.1 += s.a;
+            sum.2 += s.b;
+            sum.3 += 1;
+        }
+        let mut moved = false;
+        for (center, sum) in centers.iter_mut().zip(sums) {
+            if sum.3 == 0 {
+                continue;
+            }
+            let next = Lab { l: sum.0 / sum.3 as f32, a: sum.1 / sum.3 as f32, b: sum.2 / sum.3 as f32 };
+            moved |= delta_e(*center, next) > 0.01;
+            *center = next;
+        }
+        if !moved {
+            break;
+        }
+    }
+
+    let mut out: Vec<[u8; 3]> = centers.into_iter().map(lab_to_rgb).collect();
+    out.sort();
+    out.dedup();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_lab(rgb: [u8; 3], expected: [f32; 3]) {
+        let lab = rgb_to_lab(rgb);
+        for (got, want) in [lab.l, lab.a, lab.b].into_iter().zip(expected) {
+            assert!((got - want).abs() < 0.01, "{rgb:?} converted to {lab:?}, expected {expected:?}");
+        }
+    }
+
+    #[test]
+    fn rgb_to_lab_matches_reference_values() {
+        // sRGB primaries and extremes under D65, from Bruce Lindbloom's calculator
+        assert_lab([0, 0, 0], [0.0, 0.0, 0.0]);
+        assert_lab([255, 255, 255], [100.0, 0.0, 0.0]);
+        assert_lab([255, 0, 0], [53.2408, 80.0925, 67.2032]);
+        assert_lab([0, 255, 0], [87.7347, -86.1827, 83.1793]);
+        assert_lab([0, 0, 255], [32.2970, 79.1875, -107.8602]);
+    }
+
+    #[test]
+    fn delta_e_matches_reference_pairs() {
+        // Pairs 1, 7, 25 and 34 of Sharma's CIEDE2000 test data, with their CIE76 distance
+        let pairs = [
+            ([50.0, 2.6772, -79.7751], [50.0, 0.0, -82.7485], 4.0011),
+            ([50.0, 0.0, 0.0], [50.0, -1.0, 2.0], 2.2361),
+            ([60.2574, -34.0099, 36.2677], [60.4626, -34.1751, 39.4387], 3.1819),
+            ([22.7233, 20.0904, -46.6940], [23.0331, 14.9730, -42.5619], 6.5847),
+        ];
+        for (a, b, expected) in pairs {
+            let (a, b) = (Lab { l: a[0], a: a[1], b: a[2] }, Lab { l: b[0], a: b[1], b: b[2] });
+            assert!((delta_e(a, b) - expected).abs() < 1e-3, "{a:?} to {b:?} is {}, expected {expected}", delta_e(a, b));
+            assert_eq!(delta_e(a, b), delta_e(b, a));
+        }
+        // Red to blue through the conversion
+        assert!((delta_e(rgb_to_lab([255, 0, 0]), rgb_to_lab([0, 0, 255])) - 176.3141).abs() < 0.01);
+    }
+
+    #[test]
+    fn lab_round_trips_to_the_same_rgb() {
+        for r in (0..=255).step_by(15) {
+            for g in (0..=255).step_by(15) {
+                for b in (0..=255).step_by(15) {
+                    assert_eq!(lab_to_rgb(rgb_to_lab([r, g, b])), [r, g, b]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_picks_the_perceptually_closest() {
+        let palette = [[0, 0, 0], [255, 255, 255], [200, 30, 30], [30, 30, 200]];
+        assert_eq!(nearest([240, 240, 240], &palette), [255, 255, 255]);
+        assert_eq!(nearest([180, 60, 40], &palette), [200, 30, 30]);
+        let rgb_nearest = |t: [u8; 3]| *palette.iter().min_by_key(|p| (0..3).map(|i| (t[i] as i32 - p[i] as i32).pow(2)).sum::<i32>()).unwrap();
+        assert_eq!(rgb_nearest([240, 90, 250]), [255, 255, 255]);
+        assert_eq!(nearest([240, 90, 250], &palette), [30, 30, 200]); // Closer to white in RGB, but a saturated violet reads as blue
+        assert_eq!(nearest([30, 30, 200], &palette), [30, 30, 200]);
+    }
+
+    #[test]
+    fn nearest_with_an_empty_palette_keeps_the_color() {
+        assert_eq!(nearest([12, 34, 56], &[]), [12, 34, 56]);
+    }
+}
@@ -0,0 +1,13 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The pieces of SVGIZE that are useful outside of a run, mostly so output can be checked with the same metric the search used
+
+pub mod color;
+pub mod metric;
+pub mod preview;
+pub mod render;
+pub mod symbol;
+
+pub use metric::{compare, Metric};
@@ -1,66 +1,617 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
-#![feature(f16)]
+use std::{cell::RefCell, cmp::Reverse, collections::HashMap, f32::consts::PI, fs, hash::{DefaultHasher, Hash, Hasher}, io::{Cursor, Read, Write}, path::{Path, PathBuf}, process::exit, sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Mutex}};
 
-use std::{borrow::Cow, collections::HashMap, f32::consts::PI, fs::{self, File}, io::Cursor, path::{Path, PathBuf}, process::exit, u32};
-
-use clap::Parser;
-use imageproc::geometric_transformations::{rotate_about_center, Interpolation};
-use rand::{prelude::*, rngs::OsRng, TryRngCore};
-use image::{imageops::{self, resize, FilterType::{self, Lanczos3}}, ImageReader, Rgb, RgbImage, Rgba, RgbaImage};
+use clap::{ArgGroup, Parser, Subcommand, ValueEnum};
+use image_evo_filter::{color, compare, metric, preview, render, symbol, Metric};
+use imageproc::geometric_transformations::{rotate, Interpolation};
+use rand::{distr::weighted::WeightedIndex, prelude::*, rngs::OsRng, TryRngCore};
+use image::{imageops::{self, resize, FilterType::{self, Lanczos3}}, GenericImage, GrayImage, ImageFormat, ImageReader, Luma, Rgb, RgbImage, Rgba, RgbaImage};
+use base64::prelude::*;
 use colored::Colorize;
 use rand_xoshiro::Xoshiro256PlusPlus;
-use rayon::{prelude::*, ThreadPoolBuilder};
-use regex::Regex;
+use rayon::prelude::*;
 use walkdir::WalkDir;
-use xmltree::Element;
+
+static LOG_TO_STDERR: AtomicBool = AtomicBool::new(false); // Set by --stream-jsonl, which needs stdout to itself
+
+/// println, or eprintln while --stream-jsonl owns stdout
+macro_rules! log {
+    ($($arg:tt)*) => {
+        if LOG_TO_STDERR.load(Ordering::Relaxed) { eprintln!($($arg)*) } else { println!($($arg)*) }
+    };
+}
+
+struct Duotone {
+    mask: GrayImage, // Alpha of only the secondary parts, same size as the fragment image
+    centroid: (f32, f32) // Center of mass of the mask, 0.0-1.0 of the fragment
+}
 
 struct FragmentImage {
     pub im: RgbaImage,
-    pub src_svg: PathBuf
+    pub src_svg: PathBuf,
+    pub secondary: Option<Duotone>,
+    pub kept: Option<RgbaImage>, // Only the parts that keep their own color, same size as the fragment image
+    pub aliases: Vec<PathBuf>, // Identical fragments merged into this one
+    pub inline: Option<String>, // Source of fragments that aren't loose files, like ones from --fragments-from-zip
+    pub primitive: Option<(Primitive, f32)>, // Drawn as this element with this aspect instead of a <use>, like the --base-shape rect
+    pub rotation_invariant: bool // Looks the same at any angle, never rotated
 }
 
-struct ImageSetting<'a> { // the image pasted on and all the info abt it
-    src_svg: Cow<'a, PathBuf>,
+#[derive(Clone, Copy, PartialEq)]
+struct ImageSetting { // the image pasted on and all the info abt it
+    fragment: usize, // Index into the loaded fragments
     size: u32, // Pixel width
     center_x: u32,
     center_y: u32,
     color: [u8; 3], // Will substitute all pixels for this but preserve alpha of the original
-    rotation: f16, // 0.0-2pi
+    rotation: f32, // 0.0-2pi
+    mirror: (bool, bool), // Flipped (horizontally, vertically) before rotating
+    secondary: Option<[u8; 3]>, // Color of the secondary parts of duotone fragments
+    pinned: bool, // From --pin, placed no matter the score and left alone by --refine
 }
-struct ImageObj<'a> { // The image used
+struct ImageObj { // The image used
     im: RgbaImage,
     topleft_x_pos: i64,
     topleft_y_pos: i64,
-    settings: ImageSetting<'a>
+    settings: ImageSetting
+}
+
+/// Which placed shapes overlap which part of the canvas, so changing one shape only needs the shapes around it recomposited
+struct ShapeGrid {
+    cell: u32,
+    cols: u32,
+    rows: u32,
+    cells: Vec<Vec<usize>>
+}
+
+impl ShapeGrid {
+    fn new(width: u32, height: u32, cell: u32) -> Self {
+        let (cols, rows) = (width.div_ceil(cell), height.div_ceil(cell));
+        ShapeGrid { cell, cols, rows, cells: vec![vec![]; (cols * rows) as usize] }
+    }
+
+    /// Cells touched by a (left, top, right, bottom) box, right and bottom exclusive
+    fn cells_in(&self, (left, top, right, bottom): (i64, i64, i64, i64)) -> impl Iterator<Item = usize> {
+        let clamp = |v: i64, max: u32| (v.max(0) as u32 / self.cell).min(max - 1);
+        let (x0, y0) = (clamp(left, self.cols), clamp(top, self.rows));
+        let (x1, y1) = (clamp(right - 1, self.cols), clamp(bottom - 1, self.rows));
+        let cols = self.cols;
+        let empty = right <= 0 || bottom <= 0 || left >= (self.cols * self.cell) as i64 || top >= (self.rows * self.cell) as i64;
+        (y0..=y1).flat_map(move |y| (x0..=x1).map(move |x| (y * cols + x) as usize)).filter(move |_| !empty)
+    }
+
+    fn insert(&mut self, shape: usize, bounds: (i64, i64, i64, i64)) {
+        for cell in self.cells_in(bounds).collect::<Vec<_>>() {
+            self.cells[cell].push(shape);
+        }
+    }
+
+    fn remove(&mut self, shape: usize, bounds: (i64, i64, i64, i64)) {
+        for cell in self.cells_in(bounds).collect::<Vec<_>>() {
+            self.cells[cell].retain(|s| *s != shape);
+        }
+    }
+
+    /// Shapes that may overlap the box, in placement (so compositing) order
+    fn query(&self, bounds: (i64, i64, i64, i64)) -> Vec<usize> {
+        let mut shapes: Vec<usize> = self.cells_in(bounds).flat_map(|cell| self.cells[cell].iter().copied()).collect();
+        shapes.sort_unstable();
+        shapes.dedup();
+        shapes
+    }
+}
+
+impl ImageObj {
+    fn bounds(&self) -> (i64, i64, i64, i64) {
+        (self.topleft_x_pos, self.topleft_y_pos, self.topleft_x_pos + self.im.width() as i64, self.topleft_y_pos + self.im.height() as i64)
+    }
 }
 
 fn similarity_range(s: &str) -> Result<f64, String> {
     let sim: f64  = s.parse().map_err(|_| format!("{s} is not a number"))?;
 
-    if sim <= 100.0 && sim >= 0.0 {
+    if (0.0..=100.0).contains(&sim) {
         Ok(sim)
     } else {
         Err(format!("{} is not in the range 0.0-100.0 inclusive", s))
     }
 }
 
+const SVG_FOOTER: &str = "</g></svg>";
+
+/// The fragment with this file name (without extension) or path under images/, merged duplicates included
+fn find_fragment(images: &[FragmentImage], name: &str) -> Result<usize, String> {
+    let matches: Vec<usize> = (0..images.len()).filter(|i| std::iter::once(&images[*i].src_svg).chain(&images[*i].aliases).any(|path| {
+        let path = path.with_extension("");
+        path.file_name().is_some_and(|f| f == name) || path.ends_with(name)
+    })).collect();
+    match matches[..] {
+        [fragment] => Ok(fragment),
+        [] => Err(format!("No fragment named {name}")),
+        _ => Err(format!("{name} matches {} fragments, use its path under images/ instead: {}", matches.len(), matches.iter().map(|i| images[*i].src_svg.display().to_string()).collect::<Vec<_>>().join(", ")))
+    }
+}
+
+fn dimensions(s: &str) -> Result<(u32, u32), String> {
+    let (w, h) = s.split_once('x').ok_or_else(|| format!("{s} is not in the form <w>x<h>"))?;
+    let w: u32 = w.trim().parse().map_err(|_| format!("{w} is not a whole number"))?;
+    let h: u32 = h.trim().parse().map_err(|_| format!("{h} is not a whole number"))?;
+
+    if w == 0 || h == 0 {
+        Err(format!("{s} has a zero dimension"))
+    } else {
+        Ok((w, h))
+    }
+}
+
+fn crop(s: &str) -> Result<(u32, u32, u32, u32), String> {
+    let parts: Vec<&str> = s.split(',').map(str::trim).collect();
+    let [x, y, w, h] = parts[..] else {
+        return Err(format!("{s} is not in the form <x>,<y>,<w>,<h>"));
+    };
+    let num = |v: &str| v.parse::<u32>().map_err(|_| format!("{v} is not a whole number"));
+    let (w, h) = (num(w)?, num(h)?);
+    if w == 0 || h == 0 {
+        return Err(format!("{s} has a zero dimension"));
+    }
+    Ok((num(x)?, num(y)?, w, h))
+}
+
+#[derive(Clone, Copy)]
+enum CanvasSize {
+    Source,
+    Exact(u32, u32)
+}
+
+fn canvas_size(s: &str) -> Result<CanvasSize, String> {
+    if s.trim().eq_ignore_ascii_case("source") {
+        Ok(CanvasSize::Source)
+    } else {
+        dimensions(s).map(|(w, h)| CanvasSize::Exact(w, h)).map_err(|e| format!("{e}, or source"))
+    }
+}
+
+/// A CSS length for the root <svg>'s width or height, a positive number with an optional unit (px if there's none)
+fn svg_length(s: &str) -> Result<String, String> {
+    const UNITS: [&str; 13] = ["", "px", "em", "ex", "rem", "%", "in", "cm", "mm", "pt", "pc", "vw", "vh"];
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    if !UNITS.contains(&unit) {
+        return Err(format!("{unit} is not a unit, use one of {}", UNITS[1..].join(", ")));
+    }
+    match num.parse::<f64>() {
+        Ok(n) if n > 0.0 => Ok(s.to_string()),
+        _ => Err(format!("{s} is not a positive length"))
+    }
+}
+
+/// A preserveAspectRatio value, none or an alignment like xMidYMid with an optional meet or slice
+fn preserve_aspect_ratio(s: &str) -> Result<String, String> {
+    let s = s.trim();
+    let mut parts = s.split_whitespace();
+    let align = parts.next().unwrap_or_default();
+    let valid_align = align == "none" || (align.len() == 8 && align.get(..4).is_some_and(|x| ["xMin", "xMid", "xMax"].contains(&x)) && align.get(4..).is_some_and(|y| ["YMin", "YMid", "YMax"].contains(&y)));
+    match (valid_align, parts.next(), parts.next()) {
+        (true, None, None) => Ok(align.to_string()),
+        (true, Some(fit @ ("meet" | "slice")), None) => Ok(format!("{align} {fit}")),
+        _ => Err(format!("{s} is not a preserveAspectRatio value, like none, xMidYMid or xMinYMin slice"))
+    }
+}
+
+/// Shortest decimal for an SVG coordinate, whole numbers print without a fraction
+fn svg_num(v: f32) -> String {
+    let s = format!("{v:.2}");
+    s.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+/// A JSON string literal, escaped
+fn json_string(s: &str) -> String {
+    let mut out = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c)
+        }
+    }
+    out + "\""
+}
+
+/// A byte count, optionally with a K, M or G suffix (powers of 1024)
+fn byte_size(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let (num, mult) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 1 << 30),
+        _ => (s, 1)
+    };
+    let num: f64 = num.trim().parse().map_err(|_| format!("{s} is not a size, expected a number of bytes optionally followed by K, M or G"))?;
+    Ok((num * mult as f64) as usize)
+}
+
+#[derive(Clone, Copy)]
+enum BlendBg {
+    Average,
+    Color([u8; 3])
+}
+
+impl BlendBg {
+    fn resolve(self, average: [u8; 3]) -> Rgb<u8> {
+        match self {
+            BlendBg::Average => Rgb(average),
+            BlendBg::Color(c) => Rgb(c)
+        }
+    }
+}
+
+fn blend_background(s: &str) -> Result<BlendBg, String> {
+    match s.trim().to_lowercase().as_str() {
+        "average" => Ok(BlendBg::Average),
+        "black" => Ok(BlendBg::Color([0, 0, 0])),
+        "white" => Ok(BlendBg::Color([255, 255, 255])),
+        _ => color::parse_hex(s).map(BlendBg::Color).map_err(|_| format!("{s} is not average, black, white or a #RRGGBB color"))
+    }
+}
+
+#[derive(Clone)]
+struct Pin {
+    name: String,
+    center: (f32, f32), // Source image pixels
+    size: f32,
+    rotation: f32, // Degrees
+    color: Option<[u8; 3]>
+}
+
+fn pin(s: &str) -> Result<Pin, String> {
+    let parts: Vec<&str> = s.split(',').map(str::trim).collect();
+    if !(5..=6).contains(&parts.len()) {
+        return Err(format!("{s} is not in the form <fragment>,<center x>,<center y>,<size>,<rotation>[,<#RRGGBB>]"));
+    }
+    let num = |v: &str| v.parse::<f32>().map_err(|_| format!("{v} is not a number"));
+
+    Ok(Pin {
+        name: parts[0].to_string(),
+        center: (num(parts[1])?, num(parts[2])?),
+        size: num(parts[3])?,
+        rotation: num(parts[4])?,
+        color: parts.get(5).map(|c| color::parse_hex(c)).transpose()?
+    })
+}
+
+/// Where fragment rasters and their svgs are read from, loose under images_png/ and images/ or a zip with the same layout
+enum FragmentFiles {
+    Dirs,
+    Zip(HashMap<PathBuf, Vec<u8>>) // Every file in the archive by its path inside it, icon sets are small enough to just hold
+}
+
+impl FragmentFiles {
+    fn open_zip(path: &Path) -> Result<Self, String> {
+        let mut archive = zip::ZipArchive::new(fs::File::open(path).map_err(|e| format!("Could not open {}: {e}", path.display()))?).map_err(|e| format!("{} is not a zip: {e}", path.display()))?;
+        let mut files = HashMap::new();
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i).map_err(|e| format!("Could not read {}: {e}", path.display()))?;
+            if file.is_file() && let Some(name) = file.enclosed_name() { // Skips entries that would escape the archive like ../x
+                let mut bytes = vec![];
+                file.read_to_end(&mut bytes).map_err(|e| format!("Could not read {} from {}: {e}", name.display(), path.display()))?;
+                files.insert(name, bytes);
+            }
+        }
+        if !files.keys().any(|name| name.starts_with("images_png")) {
+            return Err(format!("{} has no images_png/ folder", path.display()));
+        }
+        Ok(FragmentFiles::Zip(files))
+    }
+
+    /// Every file under images_png/
+    fn pngs(&self) -> Vec<PathBuf> {
+        match self {
+            FragmentFiles::Dirs => WalkDir::new("images_png").into_iter().filter_map(|e| e.ok()).filter(|e| e.file_type().is_file()).map(|e| e.into_path()).collect(),
+            FragmentFiles::Zip(files) => files.keys().filter(|name| name.starts_with("images_png")).cloned().collect()
+        }
+    }
+
+    fn reader(&self, png: &Path) -> Option<ImageReader<Cursor<Vec<u8>>>> {
+        let bytes = match self {
+            FragmentFiles::Dirs => fs::read(png).ok()?,
+            FragmentFiles::Zip(files) => files.get(png)?.clone()
+        };
+        let mut reader = ImageReader::new(Cursor::new(bytes));
+        reader.set_format(ImageFormat::from_path(png).ok()?); // By extension like ImageReader::open
+        Some(reader)
+    }
+
+    fn svg(&self, svg: &Path) -> Option<String> {
+        match self {
+            FragmentFiles::Dirs => None, // Read from disk when it's needed
+            FragmentFiles::Zip(files) => String::from_utf8(files.get(svg)?.clone()).ok()
+        }
+    }
+}
+
+/// The middle of an image in the pixel index coordinates imageproc rotates in. Its rotate_about_center uses (width/2, height/2), half a pixel
+/// off, which shifts every rotated shape away from where the SVG (rotating about the middle of the box) puts it
+fn pixel_center((width, height): (u32, u32)) -> (f32, f32) {
+    ((width as f32 - 1.0) / 2.0, (height as f32 - 1.0) / 2.0)
+}
+
+/// Whether the fragment (and its duotone mask) looks the same turned 90, 45 and 30 degrees, which only round shapes survive
+fn is_rotation_invariant(im: &RgbaImage, mask: Option<&GrayImage>) -> bool {
+    if im.width() != im.height() {
+        return false; // Rotating would change where it reaches
+    }
+    let alpha = GrayImage::from_fn(im.width(), im.height(), |x, y| Luma([im.get_pixel(x, y)[3]]));
+    let invariant = |layer: &GrayImage| {
+        let total: f64 = layer.pixels().map(|p| p[0] as f64).sum();
+        total > 0.0 && [90.0f32, 45.0, 30.0].iter().all(|deg| {
+            let turned = rotate(layer, pixel_center(layer.dimensions()), deg.to_radians(), Interpolation::Bilinear, Luma([0]));
+            let diff: f64 = layer.pixels().zip(turned.pixels()).map(|(a, b)| (a[0] as f64 - b[0] as f64).abs()).sum();
+            diff / total < 0.03 // Interpolation alone makes up some of it
+        })
+    };
+    invariant(&alpha) && mask.is_none_or(invariant)
+}
+
+/// Dimensions of a fragment after --max-fragment-size, aspect ratio is kept
+fn capped_size(width: u32, height: u32, max: Option<u32>) -> (u32, u32) {
+    match max {
+        Some(max) if width.max(height) > max => {
+            let scale = max as f32 / width.max(height) as f32;
+            (((width as f32 * scale).round() as u32).max(1), ((height as f32 * scale).round() as u32).max(1))
+        },
+        _ => (width, height)
+    }
+}
+
+/// Summed absolute RGB error of every grid cell, row major, used to send shapes to the cells that need them most
+fn grid_errors(input: &RgbaImage, dest: &RgbaImage, cols: u32, rows: u32) -> Vec<f64> {
+    let mut errors = vec![0.0; (cols * rows) as usize];
+    for (x, y, px) in input.enumerate_pixels() {
+        let cell = ((y * rows / input.height()) * cols + x * cols / input.width()) as usize;
+        let other = dest.get_pixel(x, y);
+        errors[cell] += (0..3).map(|c| (px[c] as f64 - other[c] as f64).abs()).sum::<f64>();
+    }
+    errors
+}
+
+/// Mixes the run seed with an iteration and candidate index so every candidate gets its own stream no matter which thread draws it, splitmix64 finalizer
+fn sub_seed(seed: u64, iteration: u64, candidate: u64) -> u64 {
+    let mut z = seed ^ iteration.wrapping_mul(0x9E3779B97F4A7C15) ^ candidate.wrapping_mul(0xC2B2AE3D27D4EB4F).rotate_left(31);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+/// The best scoring candidate, candidates must come in index order. Scores are already quantized so ties are common,
+/// the earliest candidate wins a tie so the pick never depends on anything but the candidates themselves
+fn pick_best<T>(metric: Metric, candidates: impl IntoIterator<Item = (T, f64, usize)>) -> Option<(T, f64, usize)> {
+    candidates.into_iter().reduce(|best, c| if metric.is_improvement(c.1, best.1) { c } else { best })
+}
+
+/// The color that, composited over dest with the shape's alpha, gets as close as possible to input in the least squares sense
+fn optimal_color(input: &RgbaImage, dest: &RgbaImage, shape: &RgbaImage, topleft_x: i64, topleft_y: i64) -> Option<[u8; 3]> {
+    let mut num = [0.0f64; 3];
+    let mut den = 0.0f64;
+    for (x, y, px) in shape.enumerate_pixels() {
+        let (cx, cy) = (topleft_x + x as i64, topleft_y + y as i64);
+        if px[3] == 0 || cx < 0 || cy < 0 || cx >= input.width() as i64 || cy >= input.height() as i64 {
+            continue;
+        }
+        let a = px[3] as f64 / 255.0;
+        let target = input.get_pixel(cx as u32, cy as u32);
+        let under = dest.get_pixel(cx as u32, cy as u32);
+        for c in 0..3 { // Result is a*color + (1-a)*under, minimizing (result - target)^2 over the mask
+            num[c] += a * (target[c] as f64 - (1.0 - a) * under[c] as f64);
+        }
+        den += a * a;
+    }
+
+    if den <= 0.0 {
+        None // Entirely off canvas
+    } else {
+        Some(num.map(|n| (n / den).round().clamp(0.0, 255.0) as u8))
+    }
+}
+
+/// Moves a placement to a canvas scaled by scale, used when --adaptive-cmpwidth steps up
+fn rescale(settings: &mut ImageSetting, scale: f32, width: u32, height: u32) {
+    settings.center_x = (((settings.center_x as f32 + 0.5) * scale) as u32).min(width - 1);
+    settings.center_y = (((settings.center_y as f32 + 0.5) * scale) as u32).min(height - 1);
+    settings.size = ((settings.size as f32 * scale).round() as u32).max(1);
+}
+
+/// Gaussian blurs a rendered shape for --feather, grown by 3 sigma on every side so the blur has room. Returns how far it grew.
+/// Blurred premultiplied like feGaussianBlur, otherwise the color of transparent pixels would bleed into duotone and kept parts
+fn feather(im: &RgbaImage, sigma: f32) -> (RgbaImage, u32) {
+    let pad = (sigma * 3.0).ceil() as u32;
+    let mut padded = RgbaImage::new(im.width() + pad * 2, im.height() + pad * 2);
+    for (x, y, px) in im.enumerate_pixels() {
+        let a = px[3] as u32;
+        padded.put_pixel(x + pad, y + pad, Rgba([(px[0] as u32 * a / 255) as u8, (px[1] as u32 * a / 255) as u8, (px[2] as u32 * a / 255) as u8, px[3]]));
+    }
+    let mut blurred = imageops::blur(&padded, sigma);
+    for px in blurred.pixels_mut() {
+        let a = px[3] as u32;
+        if a > 0 {
+            for c in 0..3 {
+                px[c] = (px[c] as u32 * 255 / a).min(255) as u8;
+            }
+        }
+    }
+    (blurred, pad)
+}
+
+/// Flips horizontally and/or vertically, for --mirror-fragments
+fn flip<I: GenericImage>(im: &mut I, (horizontal, vertical): (bool, bool)) {
+    if horizontal {
+        imageops::flip_horizontal_in_place(im);
+    }
+    if vertical {
+        imageops::flip_vertical_in_place(im);
+    }
+}
+
+/// Debug output is best effort, a full disk shouldn't end the run
+fn save_debug(im: &RgbaImage, path: &Path) {
+    if let Err(e) = im.save(path) {
+        log!("{}", format!("Could not write debug image {}: {e}", path.display()).yellow());
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum OutputFormat {
+    Svg,
+    /// Converted from the generated SVG, which is still written next to it
+    Pdf,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum SizeReference {
+    /// The shorter side of the image
+    Shorter,
+    /// The longer side, the behavior before this option existed. On wide or tall images most shapes end up far bigger than the short side
+    Longer,
+    /// The diagonal of the image
+    Diagonal,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum SortEmit {
+    /// Largest first, the same as --reorder-emit
+    Size,
+    /// Darkest first so lighter shapes end up on top, gives overlapping shapes a layered look. Moves shapes far from the order they were scored in, so the SVG can score up to about 5 points off the run
+    Luminance,
+    /// Lightest first, drifts from the run's score like luminance
+    LuminanceReverse,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Mirror {
+    Horizontal,
+    Vertical,
+    /// Either or both, four variants of every fragment
+    Both,
+}
+
+/// Shapes drawn directly instead of loaded from fragment files, for --primitives
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Primitive {
+    Rect,
+    Ellipse,
+    Triangle,
+}
+
+impl Primitive {
+    /// Height over width of the variants generated for each kind, rotation covers the other orientations
+    fn aspects(self) -> &'static [f32] {
+        match self {
+            Primitive::Rect | Primitive::Ellipse => &[1.0, 0.5, 0.25],
+            Primitive::Triangle => &[1.0, 0.5]
+        }
+    }
+
+    fn name(self, aspect: f32) -> String {
+        let kind = match self {
+            Primitive::Rect => "rect",
+            Primitive::Ellipse => "ellipse",
+            Primitive::Triangle => "triangle"
+        };
+        if aspect == 1.0 { kind.to_string() } else { format!("{kind}-{}x1", (1.0 / aspect).round()) }
+    }
+
+    /// The element without its closing, centered in a size x size box
+    fn element(self, aspect: f32, size: f32) -> String {
+        let (top, bottom) = (size * (1.0 - aspect) / 2.0, size * (1.0 + aspect) / 2.0);
+        match self {
+            Primitive::Rect if aspect == 1.0 => format!("<rect width=\"{}\" height=\"{}\"", svg_num(size), svg_num(size)),
+            Primitive::Rect => format!("<rect y=\"{}\" width=\"{}\" height=\"{}\"", svg_num(top), svg_num(size), svg_num(size * aspect)),
+            Primitive::Ellipse => format!("<ellipse cx=\"{}\" cy=\"{}\" rx=\"{}\" ry=\"{}\"", svg_num(size / 2.0), svg_num(size / 2.0), svg_num(size / 2.0), svg_num(size * aspect / 2.0)),
+            Primitive::Triangle => format!("<polygon points=\"{},{} {},{} 0,{}\"", svg_num(size / 2.0), svg_num(top), svg_num(size), svg_num(bottom), svg_num(bottom))
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum RotationInterp {
+    /// Crispest and fastest, good for sharp edged fragments
+    Nearest,
+    Bilinear,
+    /// Smoothest but rings a little around hard edges
+    Bicubic,
+}
+
+impl From<RotationInterp> for Interpolation {
+    fn from(interp: RotationInterp) -> Self {
+        match interp {
+            RotationInterp::Nearest => Interpolation::Nearest,
+            RotationInterp::Bilinear => Interpolation::Bilinear,
+            RotationInterp::Bicubic => Interpolation::Bicubic,
+        }
+    }
+}
+
+#[derive(clap::Args)]
+struct VerifyArgs {
+    /// The SVG to check
+    #[arg(long)]
+    svg: String,
+
+    /// The image it was generated from
+    #[arg(long)]
+    input: String,
+
+    /// Width both are compared at, should match the --cmpwidth of the run for the score to mean the same thing
+    #[arg(short, long, default_value_t=384)]
+    cmpwidth: u32,
+
+    /// How similarity is scored, should match the run (rgba-hybrid if it used --no-background)
+    #[arg(long, value_enum, default_value_t=Metric::Hybrid)]
+    metric: Metric,
+
+    /// Color transparency is blended against when scoring, should match the run
+    #[arg(long, default_value="average", value_parser=blend_background)]
+    blend_bg: BlendBg,
+
+    /// Minimum match percentage (0.0-100.0) to pass, exits with an error below it (for mse, the highest error percentage). Without it the score is only printed
+    #[arg(long, value_parser=similarity_range)]
+    threshold: Option<f64>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check an existing output still matches its source, without generating anything
+    Verify(VerifyArgs),
+}
+
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
+#[command(group(ArgGroup::new("palette_source").args(["palette", "quantize"])))]
+#[command(subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Input file
-    input: String,
+    #[arg(required = true)]
+    input: Option<String>,
 
-    /// Output file, will output in the same folder by default. Should be an svg, if not an svg it will add the extension.
+    /// Output file, will output in the same folder by default. Should be an svg (or a pdf with --format pdf), if not it will add the extension. A .pdf output implies --format pdf
     #[arg(short, long)]
     output: Option<String>,
 
+    /// Output format, the svg is always written and is the source for any other format
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+
     /// Minumum number of shapes to place, depending on the image you may want more than default, set to 0 to disable
     #[arg(short, long, default_value_t=500)]
     shapes: u32,
 
-    /// Minimum match percentage (0.0-100.0), if used with --shapes will stop only when both conditions are met, 100% is impossible and normally 25-50% match is enough. If set to 100% it will run until it fails the number of times specified by --failmax
+    /// Minimum match percentage (0.0-100.0), if used with --shapes will stop only when both conditions are met, 100% is impossible and normally 25-50% match is enough. If set to 100% it will run until it fails the number of times specified by --failmax. With --metric mse it's the error percentage to get down to instead, and 0% runs until --failmax
     #[arg(short, long, value_parser=similarity_range)]
     matchscore: Option<f64>,
 
@@ -72,193 +623,1161 @@ struct Args {
     #[arg(short, long, default_value_t=100)]
     failmax: u32,
 
+    /// Skip the search entirely if the starting canvas (background plus any --base-shape and --pin shapes) already scores this match percentage, even if --shapes asks for more. For inputs that are close to flat, where the background alone is good enough
+    #[arg(long, value_parser=similarity_range)]
+    score_floor: Option<f64>,
+
+    /// When --failmax is hit before the target is met, start over on the failure count with a fresh seed up to this many times instead of stopping. Nothing placed is lost, the search just continues with new randomness
+    #[arg(long, default_value_t=0)]
+    restart_budget: u32,
+
     /// Number of images to try in each iteration, more will be slower but choose more optimal images and have failed iterations less often
     #[arg(short, long, default_value_t=16)]
     imgcnt: u32,
+
+    /// Restrict shape colors to this comma separated list of hex colors (e.g. "#1d2b53,#ff004d"), each sampled color is replaced by the perceptually nearest one
+    #[arg(long, value_delimiter=',', value_parser=color::parse_hex, conflicts_with="quantize")]
+    palette: Vec<[u8; 3]>,
+
+    /// Restrict shape colors to the n most representative colors of the input image, like --palette but the palette is picked for you
+    #[arg(long)]
+    quantize: Option<usize>,
+
+    /// Never place more than n shapes of any one palette color, forces the search to spread shapes across the palette for a poster-like look. Needs --palette or --quantize
+    #[arg(long, requires="palette_source")]
+    max_per_color: Option<u32>,
+
+    /// Don't emit the background rect, the output will be transparent wherever shapes don't cover it (and is scored that way unless --metric says otherwise)
+    #[arg(long)]
+    no_background: bool,
+
+    /// Start from a gaussian blur of the input (sigma in pixels at --cmpwidth) instead of a flat color, embedded under the shapes as a small PNG. Shapes then only have to add detail instead of rebuilding large gradients, which can take far fewer of them on photos. The base ignores --palette. 0 disables it
+    #[arg(long, default_value_t=0.0, conflicts_with="no_background")]
+    blur_base: f32,
+
+    /// How similarity is scored, defaults to hybrid (or rgba-hybrid with --no-background)
+    #[arg(long, value_enum)]
+    metric: Option<Metric>,
+
+    /// Color transparent parts of the input and canvas are blended against when scoring: average (the input's average color), black, white or a #RRGGBB color. Average suits inputs without alpha, with --no-background you likely want the color the SVG will be shown on
+    #[arg(long, default_value="average", value_parser=blend_background)]
+    blend_bg: BlendBg,
+
+    /// After saving, rasterize the SVG and check it scores within --verify-tolerance of the internal result, exits with an error if not. Catches the SVG and the internal raster drifting apart
+    #[arg(long)]
+    verify: bool,
+
+    /// Allowed difference in match percentage between the rasterized SVG and the internal result for --verify. The internal raster resamples and rotates fragment images where the renderer draws the vectors, which alone drifts up to about 2 points at a small --cmpwidth
+    #[arg(long, default_value_t=2.0)]
+    verify_tolerance: f64,
+
+    /// Decimal places of the rotation (in degrees) written to the SVG, rotation is kept at full precision internally
+    #[arg(long, default_value_t=3)]
+    rotation_precision: usize,
+
+    /// Also place fragments flipped, each candidate is flipped at random. Doubles the variety of small fragment sets for free, the SVG reuses the same symbol with a scale(-1) (both if no direction is given)
+    #[arg(long, value_enum, num_args=0..=1, default_missing_value="both")]
+    mirror_fragments: Option<Mirror>,
+
+    /// Place plain shapes instead of fragments, drawn and emitted as <rect>, <ellipse> and <polygon> elements so images_png/ and images/ aren't needed at all. Takes one or more kinds separated by commas, each in a few aspect ratios (rect, rect-2x1, rect-4x1, ellipse, ellipse-2x1, ellipse-4x1, triangle, triangle-2x1) which --pin can name too
+    #[arg(long, value_enum, value_delimiter=',')]
+    primitives: Vec<Primitive>,
+
+    /// Interpolation used when rotating shapes for scoring, this is on the hot path so nearest and bilinear are noticeably faster. Only affects the internal raster, the SVG is rotated by whatever renders it
+    #[arg(long, value_enum, default_value_t=RotationInterp::Bicubic)]
+    rotation_interp: RotationInterp,
+
+    /// Write shapes to the SVG largest first instead of in the order they were placed, so small detail is never buried under a big shape accepted later. Only the SVG is reordered which changes how it composites, combine with --verify to check it still matches
+    #[arg(long, conflicts_with="sort_emit")]
+    reorder_emit: bool,
+
+    /// Order shapes are written to the SVG in, like --reorder-emit this only changes how the SVG composites and not what was scored. Size stays within --verify-tolerance of the run, the luminance orders are purely a look and drift up to about 5 points from it, so they can fail --verify
+    #[arg(long, value_enum)]
+    sort_emit: Option<SortEmit>,
+
+    /// Instead of the input color at the shape's center, use the color that moves the area under the shape closest to the input given what's already placed (a least squares fit over the shape's mask). Duotone fragments keep their sampled colors. Lets shapes correct earlier overshoot so it can reach a score with fewer shapes, at the cost of an extra pass over every candidate's pixels which is noticeable with large shapes
+    #[arg(long)]
+    optimal_color: bool,
+
+    /// Also reject shapes that make any one RGB channel worse over the area they cover, by more than this many percent (0 if no number is given), even when the overall score improves. Keeps the hybrid metrics from trading one channel for another and leaving color casts, at some cost in speed
+    #[arg(long, num_args=0..=1, default_missing_value="0")]
+    per_channel_guard: Option<f64>,
+
+    /// Before scoring a candidate for real, guess with cheap integer math how much it brings the colors under its bounding box closer to the input, and skip the full compare unless that's at least this many percent (0 if no number is given, negative lets slightly worse ones through). Much faster per candidate, but it only looks at color so it can throw away shapes the metric would have liked. How many compares were skipped is reported at the end
+    #[arg(long, num_args=0..=1, default_missing_value="0", allow_negative_numbers=true)]
+    prefilter: Option<f64>,
+
+    /// Only accept shapes that are worth their bytes: the nth shape has to raise the match by at least k*n/1000 percentage points (with 1.0 the 100th shape needs +0.1%), which gets stricter as the SVG grows. Runs end through --failmax once shapes stop paying for themselves, choose it and --shapes with that in mind
+    #[arg(long)]
+    efficiency: Option<f64>,
+
+    /// When every candidate in an iteration fails, retry up to this many times with the largest allowed shape size halved each round before counting it as a failure. Small detail shapes often still fit when large ones no longer do, so this keeps nearly finished images from hitting --failmax early
+    #[arg(long, default_value_t=0)]
+    fallback_rounds: u32,
+
+    /// Snap shapes to the centers of a <cols>x<rows> grid sized to fill one cell each, for a mosaic look. Cells are picked weighted by how far off they still are rather than uniformly
+    #[arg(long, value_parser=dimensions)]
+    grid: Option<(u32, u32)>,
+
+    /// How far (as a fraction of a cell) --grid shapes may stray from the cell center and cell size, 0.0 is a perfectly regular mosaic
+    #[arg(long, default_value_t=0.0, requires="grid")]
+    grid_jitter: f32,
+
+    /// Which dimension of the image random shape sizes are drawn relative to
+    #[arg(long, value_enum, default_value_t=SizeReference::Shorter)]
+    size_reference: SizeReference,
+
+    /// Make fragment edges hard when scoring: after a fragment is scaled to a shape's size, alpha at or above this (1-255) becomes opaque and anything below becomes transparent. Normally edges stay anti-aliased, which is also how the SVG renders, so with this --verify will see some difference along edges
+    #[arg(long, value_parser=clap::value_parser!(u8).range(1..))]
+    alpha_threshold: Option<u8>,
+
+    /// Soften shape edges with a Gaussian blur of this many pixels (standard deviation, at --cmpwidth) for a painterly look. Shapes are blurred the same way when scoring and get a matching feGaussianBlur filter in the SVG
+    #[arg(long)]
+    feather: Option<f32>,
+
+    /// Rotate fragments even if they look the same at any angle. Normally those (circles, rings and the like) are found when loading and placed unrotated, rotating them only costs time and blurs them
+    #[arg(long)]
+    rotate_symmetric: bool,
+
+    /// Let shapes be larger than the image in either dimension, normally they're clamped to the shorter side
+    #[arg(long)]
+    allow_oversize: bool,
+
+    /// Seed for all randomness, the same seed and options give the same output. Picked at random and printed if not given
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Generate and score each iteration's candidates on all cores, results are identical to a serial run with the same seed
+    #[arg(long)]
+    parallel: bool,
+
+    /// Start comparing at 1/8 of --cmpwidth and double the resolution every time the score plateaus (see --plateau) or that resolution's even share of --shapes is placed, re-scoring everything placed so far. Early huge shapes are scored fast at low resolution and detail still gets the full resolution. --shapes, --matchscore and --failmax only apply once at the full --cmpwidth
+    #[arg(long)]
+    adaptive_cmpwidth: bool,
+
+    /// Consecutive failed iterations at a reduced resolution before --adaptive-cmpwidth moves up to the next one
+    #[arg(long, default_value_t=10, requires="adaptive_cmpwidth")]
+    plateau: u32,
+
+    /// Write a PNG of the canvas after every accepted placement to this directory, named by placement number and score. Off by default, for working out why the search stalls
+    #[arg(long)]
+    debug_dir: Option<PathBuf>,
+
+    /// With --debug-dir, also write every candidate that didn't improve the score (to a rejects subdirectory), named by iteration, candidate and score. That's up to --imgcnt images per iteration so it gets big fast
+    #[arg(long, requires="debug_dir")]
+    debug_rejects: bool,
+
+    /// Stop placing shapes once the next one would take the SVG over this many bytes. The size is tracked exactly as shapes are placed (symbols included), so the output is guaranteed to fit
+    #[arg(long)]
+    max_bytes: Option<usize>,
+
+    /// Downsample fragments larger than this many pixels on their longest side when loading them. Shapes are only ever scaled down from the fragment so anything much bigger than the largest shape you expect is wasted memory
+    #[arg(long)]
+    max_fragment_size: Option<u32>,
+
+    /// Read fragments from this zip instead of the images_png/ and images/ folders, laid out the same way inside it
+    #[arg(long)]
+    fragments_from_zip: Option<PathBuf>,
+
+    /// Only place this many fragments, picked at random (from --seed, so it's reproducible) after loading. Searches a big library faster and gives a more cohesive look. The picks are listed at the start, --pin and --base-shape can still use any fragment
+    #[arg(long, value_parser=clap::value_parser!(u32).range(1..))]
+    limit_fragments: Option<u32>,
+
+    /// Refuse to start if the decoded fragments would take more memory than this (after --max-fragment-size), e.g. 512M or 2G. Checked from the image headers before anything is decoded
+    #[arg(long, value_parser=byte_size)]
+    fragment_memory_budget: Option<usize>,
+
+    /// After placing, polish every shape by trying small nudges to its position, size, rotation and color and keeping whatever improves the score. Repeats for up to this many passes (5 if no number is given) or until a pass improves nothing
+    #[arg(long, num_args=0..=1, default_missing_value="5")]
+    refine: Option<u32>,
+
+    /// Start with one shape covering the whole canvas in the input's dominant color (the biggest cluster of similar colors, not the average the background already uses). rect (the default) or the name of a fragment, like --pin. Stays a vector shape unlike --blur-base
+    #[arg(long, num_args=0..=1, default_missing_value="rect")]
+    base_shape: Option<String>,
+
+    /// Place a fragment before the search starts whether or not it helps the score, as <fragment>,<center x>,<center y>,<size>,<rotation>[,<#RRGGBB>]. The fragment is its file name without extension (or a path under images/ if that's ambiguous), position and size are in source image pixels and rotation is in degrees. The color is sampled like any other shape's if not given. Can be repeated, pins are layered in the order given
+    #[arg(long, value_parser=pin)]
+    pin: Vec<Pin>,
+
+    /// Only reconstruct this rectangle of the input, as <x>,<y>,<w>,<h> in source pixels. Everything (scoring, --cmpwidth, the SVG canvas and --canvas-size source) treats the crop as if it were the whole image, --pin positions are still in the full image's pixels
+    #[arg(long, value_parser=crop)]
+    crop: Option<(u32, u32, u32, u32)>,
+
+    /// Size of the SVG's coordinate space as <w>x<h>, or source for the input's own dimensions. Defaults to the comparison resolution. Scoring still happens at --cmpwidth, only the emitted coordinates are scaled, so this gives round numbers at the size the SVG will actually be used at
+    #[arg(long, value_parser=canvas_size)]
+    canvas_size: Option<CanvasSize>,
+
+    /// Give the root <svg> this width, like 800, 800px, 10cm or 100%. Without it (and --svg-height) the SVG only has a viewBox and takes whatever size it's embedded at. Only changes how big it's displayed, not the coordinates, see --canvas-size for those
+    #[arg(long, value_parser=svg_length)]
+    svg_width: Option<String>,
+
+    /// Give the root <svg> this height, like --svg-width
+    #[arg(long, value_parser=svg_length)]
+    svg_height: Option<String>,
+
+    /// preserveAspectRatio for the root <svg>, how it fits a box of a different aspect ratio than the image: none to stretch, or an alignment like xMidYMid (the SVG default) with an optional meet or slice
+    #[arg(long, value_parser=preserve_aspect_ratio)]
+    preserve_aspect_ratio: Option<String>,
+
+    /// Serve a page on this port of localhost that shows the SVG as it's built, refreshed as shapes are placed. Its stop button ends the run early and writes the output as if it had finished
+    #[arg(long)]
+    serve: Option<u16>,
+
+    /// Every this many placed shapes, also write the SVG so far next to the output, numbered by how many shapes it has. Handy for grabbing an early version that's already good enough
+    #[arg(long, value_parser=clap::value_parser!(u32).range(1..))]
+    preview_every: Option<u32>,
+
+    /// Stream every shape to stdout as it's placed, one compact JSON object per line, so another program can render or log the run live. Log messages move to stderr to keep the stream clean. Each line has the shape's index in placement order (--refine resends the shapes it changes under the same index), its fragment, position and size in SVG coordinates, rotation in degrees, flips, colors and the score once it's placed
+    #[arg(long)]
+    stream_jsonl: bool,
+
+    /// Write a heatmap of where the result is still off to this PNG, at the comparison resolution. It's the local error of --metric (in blocks for the SSIM based ones), stretched so the best pixel is black and the worst is the brightest
+    #[arg(long)]
+    error_map: Option<PathBuf>,
+}
+
+fn verify(args: VerifyArgs) {
+    let source = ImageReader::open(&args.input).map_err(|e| format!("Could not read {}: {e}", args.input))
+        .and_then(|reader| reader.decode().map_err(|e| format!("Could not decode {}: {e}", args.input))).unwrap_or_else(|e| {
+        log!("{}", e.red());
+        exit(1);
+    }).to_rgba8();
+    let input_image = resize(&source, args.cmpwidth, (args.cmpwidth as f32/source.width() as f32*source.height() as f32) as u32, FilterType::Triangle);
+    let avgcolor = *resize(&input_image, 1, 1, FilterType::Triangle).get_pixel(0, 0);
+    let raster = fs::read_to_string(&args.svg).map_err(|e| format!("Could not read {}: {e}", args.svg)).and_then(|svg| render::without_display_size(&svg))
+        .and_then(|svg| render::rasterize(&svg, input_image.width(), input_image.height())).unwrap_or_else(|e| {
+        log!("{}", e.red());
+        exit(1);
+    });
+
+    let score = compare(&input_image, &raster, args.metric, args.blend_bg.resolve([avgcolor[0], avgcolor[1], avgcolor[2]])) * 100.0;
+    match args.threshold {
+        Some(threshold) if args.metric.is_improvement(threshold, score) => {
+            log!("{}", format!("Verify failed: {} scores {score:.04}% against {}, worse than the {threshold:.04}% threshold", args.svg, args.input).red());
+            exit(1);
+        },
+        Some(threshold) => log!("{}", format!("Verify passed: {} scores {score:.04}% against {} (threshold {threshold:.04}%)", args.svg, args.input).bright_green()),
+        None => log!("{} scores {score:.04}% against {}", args.svg, args.input)
+    }
 }
 
 fn main() {
     let args = Args::parse();
-    let source_image: String = args.input;
-    let target_score = args.matchscore.unwrap_or_else(|| 0.0) as f64 / 100.0;
+    if let Some(Command::Verify(verify_args)) = args.command {
+        verify(verify_args);
+        return;
+    }
+    let source_image: String = args.input.unwrap(); // Only optional when there's a subcommand
+    LOG_TO_STDERR.store(args.stream_jsonl, Ordering::Relaxed);
+    let metric = args.metric.unwrap_or(if args.no_background { Metric::RgbaHybrid } else { Metric::Hybrid }); // Blending would fill the transparent areas with the blend color, which is exactly the background we aren't emitting
+    let target_score = args.matchscore.map_or(metric.initial_worst(), |m| m / 100.0);
     let target_shapes = args.shapes;
+    let format = args.format.unwrap_or(if args.output.as_ref().is_some_and(|o| o.ends_with(".pdf")) { OutputFormat::Pdf } else { OutputFormat::Svg });
     let mut outfile = args.output.unwrap_or_else(|| source_image.clone() + ".svg");
+    if let Some(stem) = outfile.strip_suffix(".pdf") {
+        outfile = stem.to_string();
+    }
     if !outfile.ends_with(".svg") {
         outfile += ".svg"
     }
 
-    if target_score <= 0.0 && target_shapes <= 0 {
-        println!("Without a target score or target shape count, the image will be blank. Please provide one.");
+    if !metric.is_improvement(target_score, metric.initial_worst()) && target_shapes == 0 {
+        log!("Without a target score or target shape count, the image will be blank. Please provide one.");
         exit(0);
     }
 
-    let mut seed_bytes = [0u8; 32];
-    OsRng.try_fill_bytes(&mut seed_bytes);
-    let mut rng = Xoshiro256PlusPlus::from_seed(seed_bytes);
+    if let Some(dir) = &args.debug_dir && let Err(e) = fs::create_dir_all(if args.debug_rejects { dir.join("rejects") } else { dir.clone() }) {
+        log!("{}", format!("Could not create debug directory {}: {e}", dir.display()).red());
+        exit(1);
+    }
+
+    let seed = args.seed.unwrap_or_else(|| OsRng.try_next_u64().unwrap());
+    log!("Seed: {seed}");
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
     //rayon::ThreadPoolBuilder::new().num_threads(num_cpus::get()).build_global().unwrap();
 
-    println!("Loading source image...");
-    let input_image = {
-        let im = ImageReader::open(source_image).unwrap().decode().unwrap().to_rgba8();
-        resize(&im, args.cmpwidth, (args.cmpwidth as f32/im.width() as f32*im.height() as f32) as u32, FilterType::Triangle)
+    log!("Loading source image...");
+    let mut source = ImageReader::open(source_image).unwrap().decode().unwrap().to_rgba8();
+    if let Some((x, y, w, h)) = args.crop {
+        if x as u64 + w as u64 > source.width() as u64 || y as u64 + h as u64 > source.height() as u64 {
+            log!("{}", format!("--crop {x},{y},{w},{h} reaches outside the {}x{} image", source.width(), source.height()).red());
+            exit(1);
+        }
+        source = imageops::crop_imm(&source, x, y, w, h).to_image(); // From here on the crop is the whole image
+    }
+    let source_height = |width: u32| (width as f32/source.width() as f32*source.height() as f32) as u32;
+    let resize_source = |width: u32| resize(&source, width, source_height(width), FilterType::Triangle);
+    let widths: Vec<u32> = if args.adaptive_cmpwidth {
+        [8, 4, 2].iter().map(|d| args.cmpwidth / d).filter(|w| *w >= 16).chain([args.cmpwidth]).collect()
+    } else {
+        vec![args.cmpwidth]
     };
+    let mut width_step = 0;
+    let mut input_image = resize_source(widths[0]);
     let avgcolor = {
         let tmp = resize(&input_image, 1, 1, FilterType::Triangle);
-        tmp.get_pixel(0, 0).clone()
+        *tmp.get_pixel(0, 0)
     }.0;
-    let mut dest_image = RgbaImage::from_pixel(input_image.width(), input_image.height(), Rgba([avgcolor[0], avgcolor[1], avgcolor[2], 255]));
-    println!("Loaded source image");
-
-    println!("Loading fragment images...");
-    let images: Vec<FragmentImage> = WalkDir::new("images_png").into_iter().par_bridge().filter_map(|e| e.ok()).filter_map(|path| {
-        if path.metadata().unwrap().is_file() {
-            let im = ImageReader::open(path.path()).ok()?.decode().ok()?;
-            println!("{}{}", "Loaded fragment image: ".italic().bright_black(), format!("{}", path.path().display()).italic().bright_black());
-
-            Some(FragmentImage {
-                im: im.to_rgba8(),
-                src_svg: {
-                    let mut f = path.path().to_path_buf();
-                    f.set_extension("svg");
-                    Path::new("images/").join(f.strip_prefix("images_png").ok().unwrap()).to_path_buf()
-                }
-            })
-        } else {
-            None
+    log!("Loaded source image");
+
+    let thumbnail: Vec<[u8; 3]> = resize(&input_image, 64, (64.0/input_image.width() as f32*input_image.height() as f32).max(1.0) as u32, FilterType::Triangle).pixels().map(|p| [p[0], p[1], p[2]]).collect();
+    let palette = match args.quantize {
+        Some(n) => {
+            let palette = color::quantize(&thumbnail, n, &mut rng);
+            log!("Quantized to {} colors: {}", palette.len(), palette.iter().map(|c| format!("#{:02X}{:02X}{:02X}", c[0], c[1], c[2])).collect::<Vec<_>>().join(","));
+            palette
+        },
+        None => args.palette
+    };
+    let avgcolor = if palette.is_empty() { avgcolor } else {
+        let [r, g, b] = color::nearest([avgcolor[0], avgcolor[1], avgcolor[2]], &palette);
+        [r, g, b, avgcolor[3]]
+    };
+
+    let files = match &args.fragments_from_zip {
+        Some(path) => FragmentFiles::open_zip(path).unwrap_or_else(|e| {
+            log!("{}", e.red());
+            exit(1);
+        }),
+        None => FragmentFiles::Dirs
+    };
+    let pngs = if args.primitives.is_empty() { files.pngs() } else { vec![] }; // Primitives are all there is then
+    if let Some(budget) = args.fragment_memory_budget {
+        let predicted: usize = pngs.par_iter().filter_map(|path| {
+            let (width, height) = files.reader(path)?.into_dimensions().ok()?;
+            let (width, height) = capped_size(width, height, args.max_fragment_size);
+            Some(width as usize * height as usize * 4)
+        }).sum();
+        if predicted > budget {
+            log!("{}", format!("Fragments would take {:.01} MiB decoded, over the {:.01} MiB --fragment-memory-budget. Try a smaller --max-fragment-size", predicted as f64 / (1 << 20) as f64, budget as f64 / (1 << 20) as f64).red());
+            exit(1);
+        }
+    }
+    if args.primitives.is_empty() {
+        log!("Loading fragment images...");
+    }
+    let mut images: Vec<FragmentImage> = pngs.par_iter().filter_map(|path| {
+        let mut im = files.reader(path)?.decode().ok()?;
+        let (width, height) = capped_size(im.width(), im.height(), args.max_fragment_size);
+        if (width, height) != (im.width(), im.height()) {
+            im = im.resize_exact(width, height, Lanczos3);
         }
+        log!("{}{}", "Loaded fragment image: ".italic().bright_black(), format!("{}", path.display()).italic().bright_black());
+
+        let src_svg = {
+            let mut f = path.to_path_buf();
+            f.set_extension("svg");
+            Path::new("images/").join(f.strip_prefix("images_png").ok().unwrap()).to_path_buf()
+        };
+        let inline = files.svg(&src_svg);
+        let text = inline.clone().or_else(|| fs::read_to_string(&src_svg).ok());
+        let kept = text.as_ref().filter(|text| symbol::has_kept_colors(text)).and_then(|text| render::rasterize_styled(text, im.width(), im.height(), Some(symbol::KEEP_COLOR_ONLY_CSS)).ok());
+        let secondary = text.filter(|text| symbol::is_duotone(text)).and_then(|text| {
+            let only_secondary = render::rasterize_styled(&text, im.width(), im.height(), Some(symbol::SECONDARY_ONLY_CSS)).ok()?;
+            let mask = GrayImage::from_fn(im.width(), im.height(), |x, y| Luma([only_secondary.get_pixel(x, y)[3]]));
+            let (mut sum_x, mut sum_y, mut total) = (0.0, 0.0, 0.0);
+            for (x, y, px) in mask.enumerate_pixels() {
+                sum_x += (x as f32 + 0.5) * px[0] as f32;
+                sum_y += (y as f32 + 0.5) * px[0] as f32;
+                total += px[0] as f32;
+            }
+            (total > 0.0).then(|| Duotone { centroid: (sum_x / total / mask.width() as f32, sum_y / total / mask.height() as f32), mask })
+        });
+
+        let im = im.to_rgba8();
+        let rotation_invariant = !args.rotate_symmetric && kept.is_none() && is_rotation_invariant(&im, secondary.as_ref().map(|duo| &duo.mask)); // An accent can be anywhere on the fragment
+        Some(FragmentImage {
+            im,
+            src_svg,
+            secondary,
+            kept,
+            aliases: vec![],
+            inline,
+            primitive: None,
+            rotation_invariant
+        })
     }).collect();
-    println!("Loaded {} fragment images successfully", images.len());
+    images.sort_by(|a, b| a.src_svg.cmp(&b.src_svg)); // Loaded in whatever order the threads finish, seeds need a fixed order
+    for (kind, aspect) in args.primitives.iter().flat_map(|kind| kind.aspects().iter().map(move |aspect| (*kind, *aspect))) {
+        let (size, _) = capped_size(256, 256, args.max_fragment_size);
+        let svg = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {size} {size}\">{}/></svg>", kind.element(aspect, size as f32));
+        let im = render::rasterize(&svg, size, size).unwrap(); // Only its alpha matters, like any fragment
+        let rotation_invariant = !args.rotate_symmetric && is_rotation_invariant(&im, None);
+        log!("{}", format!("Generated primitive: {}", kind.name(aspect)).italic().bright_black());
+        images.push(FragmentImage { im, src_svg: PathBuf::from(kind.name(aspect)), secondary: None, kept: None, aliases: vec![], inline: None, primitive: Some((kind, aspect)), rotation_invariant });
+    }
+    let loaded = images.len();
+    let mut by_hash: HashMap<u64, usize> = HashMap::new();
+    images = images.into_iter().fold(vec![], |mut unique: Vec<FragmentImage>, frag| { // Copies of the same icon would get picked more often and each add their own symbol
+        let mut hasher = DefaultHasher::new();
+        (frag.im.dimensions(), frag.im.as_raw()).hash(&mut hasher);
+        let hash = hasher.finish();
+        match by_hash.get(&hash) {
+            Some(&canonical) if unique[canonical].im == frag.im => {
+                log!("{}", format!("{} is identical to {}, using the latter", frag.src_svg.display(), unique[canonical].src_svg.display()).italic().bright_black());
+                unique[canonical].aliases.push(frag.src_svg);
+            },
+            _ => {
+                by_hash.insert(hash, unique.len());
+                unique.push(frag);
+            }
+        }
+        unique
+    });
+    let invariant = images.iter().filter(|frag| frag.rotation_invariant).count();
+    if invariant > 0 {
+        log!("{}", format!("{invariant} fragments look the same at any angle and won't be rotated").italic().bright_black());
+    }
+    if images.len() < loaded {
+        log!("Loaded {} fragment images successfully ({} duplicates merged)", images.len(), loaded - images.len());
+    } else {
+        log!("Loaded {} fragment images successfully", images.len());
+    }
+    let memory: usize = images.iter().map(|frag| frag.im.as_raw().len() + frag.secondary.as_ref().map_or(0, |duo| duo.mask.as_raw().len()) + frag.kept.as_ref().map_or(0, |kept| kept.as_raw().len())).sum();
+    log!("Fragments use {:.02} MiB", memory as f64 / (1 << 20) as f64);
+    let sampleable = match args.limit_fragments {
+        Some(limit) if (limit as usize) < images.len() => { // The picked ones go first, the rest stay loaded for --pin and --base-shape
+            let mut picked = rand::seq::index::sample(&mut rng, images.len(), limit as usize).into_vec();
+            picked.sort_unstable();
+            let rest: Vec<usize> = (0..images.len()).filter(|i| picked.binary_search(i).is_err()).collect();
+            let mut slots: Vec<Option<FragmentImage>> = images.into_iter().map(Some).collect();
+            images = picked.iter().chain(&rest).map(|i| slots[*i].take().unwrap()).collect();
+            log!("Using {limit} of {} fragments, picked by seed {seed}: {}", images.len(), images[..limit as usize].iter().map(|frag| frag.src_svg.display().to_string()).collect::<Vec<_>>().join(", "));
+            limit as usize
+        },
+        _ => images.len()
+    }; // Anything after this is only ever placed on purpose, never picked at random
+    let base_fragment = match args.base_shape.as_deref() {
+        Some("rect") => {
+            images.push(FragmentImage { im: RgbaImage::from_pixel(64, 64, Rgba([255, 255, 255, 255])), src_svg: PathBuf::from("rect"), secondary: None, kept: None, aliases: vec![], inline: None, primitive: Some((Primitive::Rect, 1.0)), rotation_invariant: false });
+            Some(images.len() - 1)
+        },
+        Some(name) => Some(find_fragment(&images, name).unwrap_or_else(|e| {
+            log!("{}", format!("Can't use {name} as the base shape: {e}").red());
+            exit(1);
+        })),
+        None => None
+    };
 
-    let mut gen_rand_im = || -> ImageObj {
-        let im_index = rng.random_range(0..images.len()) as usize;
-        let rand_center_x = rng.random_range(0..input_image.width());
-        let rand_center_y = rng.random_range(0..input_image.height());
-        let mut rand_size = (0..4).map(|_| rng.random_range(0..input_image.width().max(input_image.height()))).min().unwrap();
-        if rand_size < 1 {
-            rand_size += 1;
+    let blur_base = (args.blur_base > 0.0).then(|| {
+        let full = resize_source(args.cmpwidth);
+        let mut base = imageops::blur(&full, args.blur_base);
+        let small_width = ((args.cmpwidth as f32 / args.blur_base).ceil() as u32).clamp(1, args.cmpwidth); // About a pixel per sigma, there's no detail left to keep beyond that
+        base = resize(&base, small_width, (small_width as f32 / full.width() as f32 * full.height() as f32).round().max(1.0) as u32, FilterType::Triangle);
+        for px in base.pixels_mut() {
+            px[3] = 255; // It stands in for the background so it has to be opaque
         }
-        let mut rand_size_rotated = (rand_size as f32*rand_size as f32 * 2.0).sqrt().ceil() as u32; // Assuming a square, this is the size it would be at 45deg rotation and means the image will always fit
-        if rand_size_rotated % 2 != rand_size % 2 {
-            rand_size_rotated += 1;
+        let mut png = Cursor::new(Vec::new());
+        base.write_to(&mut png, ImageFormat::Png).unwrap();
+        log!("Blurred base is {}x{} ({} bytes)", base.width(), base.height(), png.get_ref().len());
+        (base, BASE64_STANDARD.encode(png.into_inner()))
+    });
+    let blank_image = |width: u32, height: u32| match &blur_base {
+        Some((base, _)) => resize(base, width, height, FilterType::Triangle), // Matches how resvg scales the embedded image up more closely than the sharper filters
+        None if args.no_background => RgbaImage::new(width, height), // Transparent black, what the renderer leaves where nothing is drawn
+        None => RgbaImage::from_pixel(width, height, Rgba([avgcolor[0], avgcolor[1], avgcolor[2], 255]))
+    };
+    let mut dest_image = blank_image(input_image.width(), input_image.height());
+    let mut coverage = RgbaImage::new(input_image.width(), input_image.height()); // Only alpha matters, used to tell if the background ends up hidden
+
+    let render_shape = |settings: ImageSetting, width: u32| -> ImageObj { // Width is the comparison width it's rendered at, --feather scales with it
+        let ImageSetting { fragment, size, center_x, center_y, color, rotation, mirror, secondary, .. } = settings;
+        let mut size_rotated = if rotation == 0.0 { size } else { (size as f32*size as f32 * 2.0).sqrt().ceil() as u32 }; // Assuming a square, this is the size it would be at 45deg rotation and means the image will always fit
+        if size_rotated % 2 != size % 2 {
+            size_rotated += 1;
         }
-        let rand_rot = rng.next_u32() as f32 / u32::MAX as f32 * (PI*2.0);
 
-        let pos_color = input_image.get_pixel(rand_center_x, rand_center_y);
-        let paste_offset = (rand_size_rotated as f32/2.0).floor() as u32 - (rand_size as f32/2.0).floor() as u32;
-        let src_resized = resize(&images[im_index].im, rand_size, rand_size, Lanczos3);
-        let mut im_tmp = RgbaImage::from_pixel(rand_size_rotated, rand_size_rotated, Rgba([pos_color[0], pos_color[1], pos_color[2], 0]));
+        let paste_offset = (size_rotated as f32/2.0).floor() as u32 - (size as f32/2.0).floor() as u32;
+        let mut src_resized = resize(&images[fragment].im, size, size, Lanczos3);
+        flip(&mut src_resized, mirror);
+        let secondary = images[fragment].secondary.as_ref().zip(secondary).map(|(duo, c)| {
+            let mut mask = resize(&duo.mask, size, size, Lanczos3);
+            flip(&mut mask, mirror);
+            (mask, c)
+        });
+        let kept = images[fragment].kept.as_ref().map(|kept| {
+            let mut kept = resize(kept, size, size, Lanczos3);
+            flip(&mut kept, mirror);
+            kept
+        });
+        let mut im_tmp = RgbaImage::from_pixel(size_rotated, size_rotated, Rgba([color[0], color[1], color[2], 0]));
 
-        for x in 0..rand_size {
-            for y in 0..rand_size {
-                im_tmp.get_pixel_mut(x+paste_offset, y+paste_offset)[3] = src_resized.get_pixel(x, y)[3];
+        for x in 0..size {
+            for y in 0..size {
+                let alpha = match args.alpha_threshold {
+                    Some(threshold) => if src_resized.get_pixel(x, y)[3] >= threshold { 255 } else { 0 },
+                    None => src_resized.get_pixel(x, y)[3]
+                };
+                let px = im_tmp.get_pixel_mut(x+paste_offset, y+paste_offset);
+                px[3] = alpha;
+                if let Some((mask, sec)) = &secondary { // Mixed by how much of this pixel's coverage is secondary
+                    let t = (mask.get_pixel(x, y)[0] as f32 / alpha.max(1) as f32).min(1.0);
+                    for c in 0..3 {
+                        px[c] = (color[c] as f32 * (1.0 - t) + sec[c] as f32 * t).round() as u8;
+                    }
+                }
+                if let Some(kept) = &kept { // Same for the kept colors, over whatever color the rest got
+                    let k = kept.get_pixel(x, y);
+                    let t = (k[3] as f32 / alpha.max(1) as f32).min(1.0);
+                    for c in 0..3 {
+                        px[c] = (px[c] as f32 * (1.0 - t) + k[c] as f32 * t).round() as u8;
+                    }
+                }
             }
         }
 
+        let im = if rotation == 0.0 { im_tmp } else { rotate(&im_tmp, pixel_center(im_tmp.dimensions()), rotation, args.rotation_interp.into(), Rgba([color[0], color[1], color[2], 0])) }; // Even a zero rotation would blur it a little
+        let (im, pad) = match args.feather.map(|sigma| sigma * width as f32 / args.cmpwidth as f32).filter(|sigma| *sigma > 0.0) {
+            Some(sigma) => feather(&im, sigma),
+            None => (im, 0)
+        };
         ImageObj {
-            im: rotate_about_center(&im_tmp, rand_rot, Interpolation::Bicubic, Rgba([pos_color[0], pos_color[1], pos_color[2], 0])),
-            topleft_x_pos: rand_center_x as i64 - (rand_size_rotated as f32/2.0).floor() as i64,
-            topleft_y_pos: rand_center_y as i64 - (rand_size_rotated as f32/2.0).floor() as i64,
-            settings: ImageSetting {
-                rotation: rand_rot as f16,
-                size: rand_size,
-                color: [pos_color[0], pos_color[1], pos_color[2]],
-                center_x: rand_center_x,
-                center_y: rand_center_y,
-                src_svg: Cow::Borrowed(&images[im_index].src_svg)
+            im,
+            topleft_x_pos: center_x as i64 - (size_rotated as f32/2.0).floor() as i64 - pad as i64,
+            topleft_y_pos: center_y as i64 - (size_rotated as f32/2.0).floor() as i64 - pad as i64,
+            settings
+        }
+    };
+
+    let sample_colors = |input_image: &RgbaImage, fragment: usize, center_x: u32, center_y: u32, size: u32, rotation: f32, mirror: (bool, bool)| -> ([u8; 3], Option<[u8; 3]>) {
+        let px = input_image.get_pixel(center_x, center_y);
+        let secondary = images[fragment].secondary.as_ref().map(|duo| { // Sampled under where the secondary parts end up after flipping, scaling and rotating
+            let flip = |v: f32, flipped: bool| if flipped { 1.0 - v } else { v };
+            let (dx, dy) = ((flip(duo.centroid.0, mirror.0) - 0.5) * size as f32, (flip(duo.centroid.1, mirror.1) - 0.5) * size as f32);
+            let (sin, cos) = rotation.sin_cos();
+            let x = (center_x as f32 + dx * cos - dy * sin).round().clamp(0.0, input_image.width() as f32 - 1.0) as u32;
+            let y = (center_y as f32 + dx * sin + dy * cos).round().clamp(0.0, input_image.height() as f32 - 1.0) as u32;
+            let px = input_image.get_pixel(x, y);
+            color::nearest([px[0], px[1], px[2]], &palette)
+        });
+        (color::nearest([px[0], px[1], px[2]], &palette), secondary)
+    };
+
+    let gen_rand_im = |rng: &mut Xoshiro256PlusPlus, input_image: &RgbaImage, dest_image: &RgbaImage, max_size: u32, cells: Option<&WeightedIndex<f64>>| -> ImageObj {
+        let im_index = rng.random_range(0..sampleable);
+        let (rand_center_x, rand_center_y, mut rand_size) = match (args.grid, cells) {
+            (Some((cols, rows)), Some(cells)) => {
+                let cell = cells.sample(rng) as u32;
+                let (cell_w, cell_h) = (input_image.width() as f32 / cols as f32, input_image.height() as f32 / rows as f32);
+                let mut jitter = || rng.random_range(-1.0..=1.0) * args.grid_jitter;
+                let x = ((cell % cols) as f32 + 0.5 + jitter() * 0.5) * cell_w;
+                let y = ((cell / cols) as f32 + 0.5 + jitter() * 0.5) * cell_h;
+                let size = (cell_w.max(cell_h) * (1.0 + jitter())).round() as u32;
+                ((x as u32).min(input_image.width() - 1), (y as u32).min(input_image.height() - 1), size.min(max_size))
+            },
+            _ => (
+                rng.random_range(0..input_image.width()),
+                rng.random_range(0..input_image.height()),
+                (0..4).map(|_| rng.random_range(0..max_size)).min().unwrap()
+            )
+        };
+        if !args.allow_oversize {
+            rand_size = rand_size.min(input_image.width().min(input_image.height()));
+        }
+        if rand_size < 1 {
+            rand_size += 1;
+        }
+        let rand_rot = rng.next_u32() as f32 / u32::MAX as f32 * (PI*2.0);
+        let rand_rot = if images[im_index].rotation_invariant { 0.0 } else { rand_rot }; // Still drawn so the rest of the candidate doesn't depend on it
+        let mirror = match args.mirror_fragments {
+            Some(Mirror::Horizontal) => (rng.random_bool(0.5), false),
+            Some(Mirror::Vertical) => (false, rng.random_bool(0.5)),
+            Some(Mirror::Both) => (rng.random_bool(0.5), rng.random_bool(0.5)),
+            None => (false, false)
+        };
+
+        let (pos_color, secondary) = sample_colors(input_image, im_index, rand_center_x, rand_center_y, rand_size, rand_rot, mirror);
+        let mut obj = render_shape(ImageSetting {
+            fragment: im_index,
+            rotation: rand_rot,
+            size: rand_size,
+            color: pos_color,
+            center_x: rand_center_x,
+            center_y: rand_center_y,
+            secondary,
+            mirror,
+            pinned: false
+        }, input_image.width());
+        if args.optimal_color && secondary.is_none() && images[im_index].kept.is_none() && let Some(fit) = optimal_color(input_image, dest_image, &obj.im, obj.topleft_x_pos, obj.topleft_y_pos) {
+            let fit = color::nearest(fit, &palette);
+            for px in obj.im.pixels_mut() {
+                px.0 = [fit[0], fit[1], fit[2], px[3]];
+            }
+            obj.settings.color = fit;
+        }
+        obj
+    };
+
+    let blend_bg = args.blend_bg.resolve([avgcolor[0], avgcolor[1], avgcolor[2]]);
+    let mut curr_score = compare(&input_image, &dest_image, metric, blend_bg);
+
+    let full_width = *widths.last().unwrap(); // Everything is emitted from here, the width the schedule ends at
+    let (canvas_width, canvas_height) = match args.canvas_size {
+        Some(CanvasSize::Source) => (source.width(), source.height()),
+        Some(CanvasSize::Exact(w, h)) => (w, h),
+        None => (full_width, source_height(full_width))
+    };
+    let scale = canvas_width as f32 / full_width as f32; // Shapes are scaled uniformly so they keep their aspect, the group stretches them vertically if the canvas has a different one
+    let stretch = canvas_height as f32 / (source_height(full_width) as f32 * scale);
+    let root_attrs: String = [("width", &args.svg_width), ("height", &args.svg_height), ("preserveAspectRatio", &args.preserve_aspect_ratio)].into_iter()
+        .filter_map(|(name, value)| value.as_ref().map(|v| format!(" {name}=\"{v}\""))).collect(); // Nothing by default, just the viewBox like always
+    let svg_header = || {
+        let mut header = format!("<svg viewBox=\"0 0 {canvas_width} {canvas_height}\"{root_attrs} xmlns=\"http://www.w3.org/2000/svg\">");
+        if let Some((_, png)) = &blur_base {
+            header += format!("<image x=\"0\" y=\"0\" width=\"{canvas_width}\" height=\"{canvas_height}\" preserveAspectRatio=\"none\" href=\"data:image/png;base64,{png}\"/>").as_str();
+        } else if !args.no_background {
+            header += format!("<rect x=\"0\" y=\"0\" width=\"100%\" height=\"100%\" fill=\"rgb({}, {}, {})\"/>", avgcolor[0], avgcolor[1], avgcolor[2]).as_str();
+        }
+        let transform = if (stretch - 1.0).abs() > 1e-4 { format!(" transform=\"scale(1 {stretch})\"") } else { String::new() };
+        if let Some(sigma) = args.feather.filter(|sigma| *sigma > 0.0) { // In the clip group's coordinates, the canvas plus the reach of the blur. A region in each <use>'s own coordinates would depend on its size and rotation
+            let (sigma, height) = (sigma * scale, source_height(full_width) as f32 * scale);
+            header += format!("<defs><filter id=\"feather\" filterUnits=\"userSpaceOnUse\" x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" color-interpolation-filters=\"sRGB\"><feGaussianBlur stdDeviation=\"{}\"/></filter></defs>",
+                svg_num(-sigma * 3.0), svg_num(-sigma * 3.0), svg_num(canvas_width as f32 + sigma * 6.0), svg_num(height + sigma * 6.0), svg_num(sigma)).as_str();
+        }
+        header + format!("<clipPath id=\"clipView\"><rect x=\"0\" y=\"0\" width=\"{canvas_width}\" height=\"{}\"/></clipPath><g clip-path=\"url(#clipView)\"{transform}>", svg_num(source_height(full_width) as f32 * scale)).as_str() // The clip is in the group's coordinates, before the stretch
+    };
+    let symbol_def = |fragment: usize, id: &str| -> String { // The fragment as a reusable symbol, emitted once before its first use
+        if images[fragment].primitive.is_some() {
+            return String::new(); // Drawn in place, nothing to reuse
+        }
+        let text = images[fragment].inline.clone().unwrap_or_else(|| fs::read_to_string(&images[fragment].src_svg).unwrap());
+        let svg = symbol::to_symbol(&text, id, images[fragment].secondary.is_some()).unwrap();
+        format!("<defs>{svg}</defs>") // Defs prevents rendering
+    };
+    let use_tag = |img: &ImageSetting, id: &str| -> String {
+        let fill = img.secondary.map(|c| format!(" fill=\"#{:06X}\"", (c[0] as u32) << 16 | (c[1] as u32) << 8 | c[2] as u32)).unwrap_or_default(); // Duotone symbols inherit this for their secondary parts
+        let mut transforms = if img.rotation == 0.0 { String::new() } else { format!(" rotate({:.*} {} {})", args.rotation_precision, img.rotation * (180.0/PI), svg_num(img.size as f32/2.0 * scale), svg_num(img.size as f32/2.0 * scale)) };
+        if img.mirror != (false, false) { // Flipped within its own box, so the rotation and position above stay the same
+            let size = svg_num(img.size as f32 * scale);
+            let (x, sx) = if img.mirror.0 { (size.as_str(), "-1") } else { ("0", "1") };
+            let (y, sy) = if img.mirror.1 { (size.as_str(), "-1") } else { ("0", "1") };
+            transforms += &format!(" translate({x} {y}) scale({sx} {sy})");
+        }
+        let (open, close) = if args.feather.is_some_and(|sigma| sigma > 0.0) { ("<g filter=\"url(#feather)\">", "</g>") } else { ("", "") }; // On a group so the filter region is in canvas coordinates
+        if let Some((kind, aspect)) = images[img.fragment].primitive {
+            return format!("{open}{} transform=\"translate({} {}){}\" fill=\"#{:06X}\"/>{close}",
+                kind.element(aspect, img.size as f32 * scale),
+                svg_num((img.center_x as i32 - (img.size as f32/2.0) as i32) as f32 * scale),
+                svg_num((img.center_y as i32 - (img.size as f32/2.0) as i32) as f32 * scale),
+                transforms,
+                (img.color[0] as u32) << 16 | (img.color[1] as u32) << 8 | img.color[2] as u32
+            );
+        }
+        format!("{open}<use x=\"0\" y=\"0\" transform=\"translate({} {}){}\" width=\"{}\" height=\"{}\" color=\"#{:06X}\"{} href=\"#{}\" />{close}",
+            svg_num((img.center_x as i32 - (img.size as f32/2.0) as i32) as f32 * scale),
+            svg_num((img.center_y as i32 - (img.size as f32/2.0) as i32) as f32 * scale),
+            transforms,
+            svg_num(img.size as f32 * scale),
+            svg_num(img.size as f32 * scale),
+            (img.color[0] as u32) << 16 | (img.color[1] as u32) << 8 | img.color[2] as u32,
+            fill,
+            id
+        )
+    };
+    let at_final_width = |mut settings: ImageSetting, from_width: u32| { // Where a placement ends up once --adaptive-cmpwidth reaches the full width, byte counts have to be taken there
+        let mut width = from_width;
+        for next in widths.iter().copied().filter(|w| *w > from_width) {
+            rescale(&mut settings, next as f32 / width as f32, next, source_height(next));
+            width = next;
+        }
+        settings
+    };
+    let sort_emit = args.sort_emit.or(args.reorder_emit.then_some(SortEmit::Size));
+    let body_cache: RefCell<(Vec<ImageSetting>, HashMap<usize, String>, String)> = RefCell::default(); // (placements already in the body, their symbol ids, the body)
+    let emit_svg = |placed: &[ImageSetting], from_width: u32| -> String {
+        let mut placed: Vec<ImageSetting> = placed.iter().map(|img| at_final_width(*img, from_width)).collect(); // Short of the full width if --max-bytes stopped the schedule early, the header is always at the full width
+        let mut cache = body_cache.borrow_mut();
+        let (emitted, symbol_ids, body) = &mut *cache;
+        if sort_emit.is_some() || !placed.starts_with(emitted) { // Sorting can move any shape and --refine can change old ones, start over
+            emitted.clear();
+            symbol_ids.clear();
+            body.clear();
+        }
+        for img in &placed[emitted.len()..] { // Symbols are numbered in placement order so --max-bytes can work them out while placing
+            let next = symbol_ids.len().to_string();
+            symbol_ids.entry(img.fragment).or_insert(next);
+        }
+        let lightness = |img: &ImageSetting| color::rgb_to_lab(img.color).l;
+        match sort_emit { // All stable, ties keep placement order
+            Some(SortEmit::Size) => placed.sort_by_key(|img| Reverse(img.size)),
+            Some(SortEmit::Luminance) => placed.sort_by(|a, b| lightness(a).total_cmp(&lightness(b))),
+            Some(SortEmit::LuminanceReverse) => placed.sort_by(|a, b| lightness(b).total_cmp(&lightness(a))),
+            None => {}
+        }
+        for img in &placed[emitted.len()..] {
+            let svgid = &symbol_ids[&img.fragment];
+            if !emitted.iter().any(|e| e.fragment == img.fragment) {
+                *body += &symbol_def(img.fragment, svgid);
             }
+            *body += &use_tag(img, svgid);
+            emitted.push(*img);
         }
+        svg_header() + body + SVG_FOOTER
+    };
+    let preview = args.serve.map(|port| {
+        let preview = preview::serve(port).unwrap_or_else(|e| {
+            log!("{}", e.red());
+            exit(1);
+        });
+        log!("{}", format!("Serving a live preview on http://127.0.0.1:{port}").bright_cyan());
+        preview
+    });
+    let publish = |placed: &[ImageSetting], from_width: u32, status: String| if let Some(preview) = &preview {
+        preview.publish(emit_svg(placed, from_width), status);
+    };
+    let stopped = || preview.as_ref().is_some_and(|p| p.stopped());
+    let stream = |index: usize, settings: &ImageSetting, from_width: u32, score: f64| if args.stream_jsonl { // Coordinates are the ones the <use> gets
+        let img = at_final_width(*settings, from_width);
+        let hex = |c: [u8; 3]| format!("\"#{:02X}{:02X}{:02X}\"", c[0], c[1], c[2]);
+        let line = format!("{{\"shape\":{index},\"fragment\":{},\"x\":{},\"y\":{},\"size\":{},\"rotation\":{:.*},\"mirror\":[{},{}],\"color\":{},\"secondary\":{},\"score\":{:.04}}}",
+            json_string(&images[img.fragment].src_svg.display().to_string()),
+            svg_num((img.center_x as i32 - (img.size as f32/2.0) as i32) as f32 * scale),
+            svg_num((img.center_y as i32 - (img.size as f32/2.0) as i32) as f32 * scale),
+            svg_num(img.size as f32 * scale),
+            args.rotation_precision,
+            img.rotation * (180.0/PI),
+            img.mirror.0,
+            img.mirror.1,
+            hex(img.color),
+            img.secondary.map_or("null".to_string(), hex),
+            score * 100.0
+        );
+        let mut stdout = std::io::stdout().lock();
+        let _ = writeln!(stdout, "{line}").and_then(|_| stdout.flush()); // If the reader went away the run still finishes and writes the output
     };
 
-    let mut curr_score = (image_compare::rgba_blended_hybrid_compare((&input_image).into(), (&dest_image).into(), Rgb([avgcolor[0], avgcolor[1], avgcolor[2]])).unwrap().score * 10000.0).floor() / 10000.0;
+    let mut out_bytes = svg_header().len() + SVG_FOOTER.len();
+    let mut symbol_bytes: HashMap<usize, (String, usize)> = HashMap::new(); // Fragment -> (symbol id, size of its defs)
+    let byte_cost = |settings: &ImageSetting, from_width: u32, symbol_bytes: &HashMap<usize, (String, usize)>| -> (String, usize, usize) { // (symbol id, defs bytes if it's new, total bytes it adds)
+        let (id, def_bytes) = symbol_bytes.get(&settings.fragment).map(|(id, _)| (id.clone(), 0)).unwrap_or_else(|| {
+            let id = symbol_bytes.len().to_string();
+            let def_bytes = symbol_def(settings.fragment, &id).len();
+            (id, def_bytes)
+        });
+        let cost = def_bytes + use_tag(&at_final_width(*settings, from_width), &id).len();
+        (id, def_bytes, cost)
+    };
 
     let mut success = 0;
     let mut failure = 0;
     let mut consec_fails = 0;
     let mut placed: Vec<ImageSetting> = vec![];
+    let mut batch: u64 = 0; // Every round of candidates gets a new one, feeds the candidate seeds
+    let mut color_counts: HashMap<[u8; 3], u32> = HashMap::new();
+    let mut fixed: Vec<ImageSetting> = vec![]; // Placed up front no matter the score, bottom to top
+    if let Some(fragment) = base_fragment {
+        let (center_x, center_y) = (input_image.width() / 2, input_image.height() / 2);
+        let size = input_image.width().max(input_image.height()); // A square this big centered on the canvas covers all of it
+        let (_, secondary) = sample_colors(&input_image, fragment, center_x, center_y, size, 0.0, (false, false));
+        let color = color::nearest(color::dominant(&thumbnail, &mut rng).unwrap_or([avgcolor[0], avgcolor[1], avgcolor[2]]), &palette);
+        fixed.push(ImageSetting { fragment, size, center_x, center_y, color, rotation: 0.0, mirror: (false, false), secondary, pinned: false });
+    }
+    for pin in &args.pin {
+        let fragment = find_fragment(&images, &pin.name).unwrap_or_else(|e| {
+            log!("{}", format!("Can't pin {}: {e}", pin.name).red());
+            exit(1);
+        });
+        let scale = input_image.width() as f32 / source.width() as f32;
+        let (crop_x, crop_y) = args.crop.map_or((0.0, 0.0), |(x, y, _, _)| (x as f32, y as f32));
+        let center_x = (((pin.center.0 - crop_x) * scale) as u32).min(input_image.width() - 1);
+        let center_y = (((pin.center.1 - crop_y) * scale) as u32).min(input_image.height() - 1);
+        let size = ((pin.size * scale).round() as u32).max(1);
+        let rotation = pin.rotation.to_radians().rem_euclid(PI * 2.0);
+        let (sampled, secondary) = sample_colors(&input_image, fragment, center_x, center_y, size, rotation, (false, false));
+        fixed.push(ImageSetting { fragment, size, center_x, center_y, color: pin.color.unwrap_or(sampled), rotation, mirror: (false, false), secondary, pinned: true });
+    }
+    for settings in &fixed {
+        let obj = render_shape(*settings, input_image.width());
+        if let Some(max) = args.max_bytes {
+            let (id, def_bytes, cost) = byte_cost(settings, input_image.width(), &symbol_bytes);
+            if out_bytes + cost > max {
+                log!("{}", format!("The base and pinned shapes alone take more than the {max} byte budget").red());
+                exit(1);
+            }
+            symbol_bytes.entry(settings.fragment).or_insert((id, def_bytes));
+            out_bytes += cost;
+        }
+        imageops::overlay(&mut dest_image, &obj.im, obj.topleft_x_pos, obj.topleft_y_pos);
+        imageops::overlay(&mut coverage, &obj.im, obj.topleft_x_pos, obj.topleft_y_pos);
+        *color_counts.entry(settings.color).or_insert(0) += 1;
+        placed.push(*settings);
+    }
+    if !fixed.is_empty() {
+        curr_score = compare(&input_image, &dest_image, metric, blend_bg);
+        log!("Placed {}{} pinned shapes ({:.04}%)", if base_fragment.is_some() { "the base shape and " } else { "" }, args.pin.len(), curr_score * 100.0);
+        for (i, settings) in placed.iter().enumerate() {
+            stream(i, settings, input_image.width(), curr_score);
+        }
+        publish(&placed, input_image.width(), format!("{} fixed shapes, {:.04}%", placed.len(), curr_score * 100.0));
+    }
+    let min_gain = |shapes: usize| args.efficiency.map_or(0.0, |k| k * shapes as f64 / 1e5); // For the shapes'th shape, in score units
+    let gain_status = |shapes: usize| args.efficiency.map(|_| format!(" (next shape needs {}{:.04}%)", if metric.higher_is_better() { "+" } else { "-" }, min_gain(shapes + 1) * 100.0).bright_black().to_string()).unwrap_or_default();
+    let mut width_schedule = vec![(widths[0], 0, curr_score)]; // (width, shapes placed before it, score when it started)
+    let prefiltered = (AtomicUsize::new(0), AtomicUsize::new(0)); // (skipped, checked) candidates, shared with the rayon threads
+    let already_good = args.score_floor.is_some_and(|floor| !metric.is_improvement(floor / 100.0, curr_score)); // Checked at the starting width, the search never gets to a higher one
+    if already_good {
+        log!("{}", format!("The starting canvas already scores {:.04}%, meeting --score-floor, so no shapes will be placed", curr_score * 100.0).yellow());
+    } else if target_shapes == 0 && !metric.is_improvement(target_score, curr_score) {
+        log!("{}", format!("The starting canvas already scores {:.04}%, meeting --matchscore, so no shapes are needed", curr_score * 100.0).yellow());
+    }
+    let mut search_seed = seed; // Replaced by a new one derived from the run seed on every restart, so restarts are reproducible too
+    let mut restarts = 0;
+    let mut quantization = metric::QUANTIZATION; // Refined when flooring is what keeps shapes that help from counting
 
-    while (curr_score < target_score || success < target_shapes) && consec_fails < args.failmax {
-        let im_best_result = (0..args.imgcnt)
-            .map(|_| gen_rand_im())
-            .enumerate()
-            .filter_map(
-                |pasteover| -> Option<(ImageObj, f64, usize)> {
-                    let mut desttmp = dest_image.clone(); // This stuff sucks man, can we fix it? YES WE CAN
-                    imageops::overlay(&mut desttmp, &pasteover.1.im, pasteover.1.topleft_x_pos, pasteover.1.topleft_y_pos);
-                    let newscore = (image_compare::rgba_blended_hybrid_compare((&input_image).into(), (&desttmp).into(), Rgb([avgcolor[0], avgcolor[1], avgcolor[2]])).unwrap().score * 1000000.0).floor() / 1000000.0;
-
-                    if newscore > curr_score {
-                        Some((pasteover.1, newscore, pasteover.0))
-                    } else {
-                        None
+    loop {
+        if already_good {
+            break;
+        }
+        if stopped() {
+            log!("{}", "Stopped from the preview page".yellow());
+            break;
+        }
+        let final_width = width_step + 1 >= widths.len();
+        let target_met = !metric.is_improvement(target_score, curr_score) && success >= target_shapes;
+        if final_width && (target_met || consec_fails >= args.failmax) {
+            if !target_met && restarts < args.restart_budget {
+                restarts += 1;
+                consec_fails = 0;
+                search_seed = sub_seed(seed, u64::MAX - restarts as u64, u64::MAX); // Far from any batch number the search itself would use
+                log!("{}", format!("Hit --failmax short of the target, restart {restarts}/{} with a fresh seed", args.restart_budget).yellow());
+                continue;
+            }
+            break;
+        }
+        let width_shapes = target_shapes / widths.len() as u32; // Each resolution gets an even share, tiny shapes nearly always help at low resolution so without this it barely moves on
+        if !final_width && (consec_fails >= args.plateau || (width_shapes > 0 && success - width_schedule[width_step].1 >= width_shapes)) {
+            // Plateaued at this resolution, move everything up to the next one and keep going
+            width_step += 1;
+            let scale = widths[width_step] as f32 / input_image.width() as f32;
+            input_image = resize_source(widths[width_step]);
+            dest_image = blank_image(input_image.width(), input_image.height());
+            coverage = RgbaImage::new(input_image.width(), input_image.height());
+            for settings in placed.iter_mut() {
+                rescale(settings, scale, input_image.width(), input_image.height());
+                let obj = render_shape(*settings, input_image.width());
+                imageops::overlay(&mut dest_image, &obj.im, obj.topleft_x_pos, obj.topleft_y_pos);
+                imageops::overlay(&mut coverage, &obj.im, obj.topleft_x_pos, obj.topleft_y_pos);
+            }
+            curr_score = metric::quantize(metric::unquantized(&input_image, &dest_image, metric, blend_bg), quantization);
+            consec_fails = 0;
+            width_schedule.push((widths[width_step], success, curr_score));
+            log!("{}", format!("Comparison width raised to {} ({:.04}% at the new width)", widths[width_step], curr_score * 100.0).bright_cyan());
+            continue;
+        }
+
+        let full_size = match args.size_reference {
+            SizeReference::Shorter => input_image.width().min(input_image.height()),
+            SizeReference::Longer => input_image.width().max(input_image.height()),
+            SizeReference::Diagonal => (input_image.width() as f32).hypot(input_image.height() as f32).ceil() as u32,
+        };
+        let cells = args.grid.and_then(|(cols, rows)| WeightedIndex::new(grid_errors(&input_image, &dest_image, cols, rows)).ok()); // Fails if nothing is off anywhere, uniform is fine then
+        let margin = min_gain(placed.len() + 1);
+        let tied: Mutex<Vec<f64>> = Mutex::default(); // Unfloored scores of candidates that floored to exactly the current score
+        let mut im_best_result = None;
+        for round in 0..=args.fallback_rounds {
+            let max_size = full_size.checked_shr(round).unwrap_or(0).max(1);
+            let evaluate = |i: u32| -> Option<(ImageObj, f64, usize)> {
+                let mut candidate_rng = Xoshiro256PlusPlus::seed_from_u64(sub_seed(search_seed, batch, i as u64));
+                let pasteover = gen_rand_im(&mut candidate_rng, &input_image, &dest_image, max_size, cells.as_ref());
+                if args.max_per_color.is_some_and(|max| color_counts.get(&pasteover.settings.color).copied().unwrap_or(0) >= max) {
+                    return None; // Color budget used up, not worth scoring
+                }
+                if let Some(min) = args.prefilter {
+                    prefiltered.1.fetch_add(1, Ordering::Relaxed);
+                    if metric::overlay_gain(&input_image, &dest_image, &pasteover.im, pasteover.topleft_x_pos, pasteover.topleft_y_pos, blend_bg) < min / 100.0 {
+                        prefiltered.0.fetch_add(1, Ordering::Relaxed);
+                        return None;
                     }
                 }
-            )
-            .max_by_key(|x| (x.1 * 1000000.0) as i32);
+                let mut desttmp = dest_image.clone(); // This stuff sucks man, can we fix it? YES WE CAN
+                imageops::overlay(&mut desttmp, &pasteover.im, pasteover.topleft_x_pos, pasteover.topleft_y_pos);
+                let raw = metric::unquantized(&input_image, &desttmp, metric, blend_bg);
+                let newscore = metric::quantize(raw, quantization);
+                if newscore == curr_score {
+                    tied.lock().unwrap().push(raw);
+                }
 
-        if im_best_result.is_some() {
-            let im = im_best_result.unwrap();
+                let accepted = metric.is_improvement_by(newscore, curr_score, margin) && args.per_channel_guard.is_none_or(|tolerance| { // Only worked out for candidates that pass otherwise
+                    let bounds = pasteover.bounds();
+                    let (before, after) = (metric::channel_errors(&input_image, &dest_image, blend_bg, bounds), metric::channel_errors(&input_image, &desttmp, blend_bg, bounds));
+                    (0..3).all(|c| after[c] <= before[c] + tolerance / 100.0)
+                });
+                if accepted {
+                    Some((pasteover, newscore, i as usize))
+                } else {
+                    if args.debug_rejects && let Some(dir) = &args.debug_dir {
+                        save_debug(&desttmp, &dir.join(format!("rejects/{batch:06}_{i:03}_{:.04}.png", newscore * 100.0)));
+                    }
+                    None
+                }
+            };
+            let results: Vec<Option<(ImageObj, f64, usize)>> = if args.parallel {
+                (0..args.imgcnt).into_par_iter().map(evaluate).collect() // Collect keeps index order, so picking the best below is the same as serial
+            } else {
+                (0..args.imgcnt).map(evaluate).collect()
+            };
+            batch += 1;
+            im_best_result = pick_best(metric, results.into_iter().flatten());
+            if im_best_result.is_some() {
+                if round > 0 {
+                    log!("{}", format!("Fallback round {round} succeeded (shapes up to {max_size}px)").italic().bright_black());
+                }
+                break;
+            }
+        }
+
+        if let Some(im) = im_best_result {
+            if let Some(max) = args.max_bytes {
+                let (id, def_bytes, cost) = byte_cost(&im.0.settings, input_image.width(), &symbol_bytes);
+                if out_bytes + cost > max {
+                    log!("{}", format!("Stopping, the next shape would take the output to {} bytes which is over the {max} byte budget", out_bytes + cost).yellow());
+                    break;
+                }
+                symbol_bytes.entry(im.0.settings.fragment).or_insert((id, def_bytes));
+                out_bytes += cost;
+            }
             curr_score = im.1;
             imageops::overlay(&mut dest_image, &im.0.im, im.0.topleft_x_pos, im.0.topleft_y_pos);
-            //dest_image.save(format!("out/{:.06}.png", im.1)); // Disabled for production, good for debug tho
+            imageops::overlay(&mut coverage, &im.0.im, im.0.topleft_x_pos, im.0.topleft_y_pos);
+            *color_counts.entry(im.0.settings.color).or_insert(0) += 1;
             placed.push(im.0.settings);
+            stream(placed.len() - 1, &im.0.settings, input_image.width(), curr_score);
             success += 1;
+            if let Some(dir) = &args.debug_dir {
+                save_debug(&dest_image, &dir.join(format!("{success:06}_{:.04}.png", im.1 * 100.0)));
+            }
             consec_fails = 0;
-            println!("Image success ({:.04}% > {:.04}%)", im.1*100.0, curr_score*100.0);
-            println!("{}/{}/{}/{} (placed/failed/consecutive fails/score)", success.to_string().bright_green(), failure.to_string().bright_red(), consec_fails.to_string().bright_yellow(), format!("{:.04}", curr_score * 100.0).bright_magenta());
+            publish(&placed, input_image.width(), format!("{success} shapes placed, {:.04}%", im.1 * 100.0));
+            if let Some(every) = args.preview_every && success % every == 0 {
+                let path = format!("{}_{success:06}.svg", outfile.strip_suffix(".svg").unwrap());
+                if let Err(e) = fs::write(&path, emit_svg(&placed, input_image.width())) {
+                    log!("{}", format!("Could not write preview {path}: {e}").yellow());
+                }
+            }
+            log!("Image success ({:.04}% {} {:.04}%)", im.1*100.0, if metric.higher_is_better() { ">" } else { "<" }, curr_score*100.0);
+            log!("{}/{}/{}/{} (placed/failed/consecutive fails/score){}", success.to_string().bright_green(), failure.to_string().bright_red(), consec_fails.to_string().bright_yellow(), format!("{:.04}", curr_score * 100.0).bright_magenta(), gain_status(placed.len()));
             continue;
         }
+        let tied = tied.into_inner().unwrap();
+        if !tied.is_empty() {
+            let curr_raw = metric::unquantized(&input_image, &dest_image, metric, blend_bg);
+            let finer = quantization * 10.0;
+            if tied.iter().any(|raw| metric.is_improvement_by(metric::quantize(*raw, finer), metric::quantize(curr_raw, finer), margin)) { // Some of them do help, flooring hid it
+                if finer <= metric::MAX_QUANTIZATION {
+                    quantization = finer;
+                    curr_score = metric::quantize(curr_raw, quantization);
+                    log!("{}", format!("Shapes that help were being floored to no change, scores are now compared in steps of 1/{quantization}").bright_cyan());
+                    continue; // Not a failure, the next round is scored at the new precision
+                }
+                if final_width {
+                    log!("{}", format!("Converged to the metric's precision at {:.04}%, what's left to gain is below what scores can tell apart", curr_score * 100.0).yellow());
+                    break;
+                }
+                consec_fails = consec_fails.max(args.plateau); // Nothing more to get at this resolution, move up
+                continue;
+            }
+        }
         failure += 1;
         consec_fails += 1;
-        println!("{} images failed", args.imgcnt);
-        println!("{}/{}/{}/{} (placed/failed/consecutive fails/score)", success.to_string().bright_green(), failure.to_string().bright_red(), consec_fails.to_string().bright_yellow(), format!("{:.04}", curr_score * 100.0).bright_magenta());
-    }
-
-    println!("Image finished!\nSaving... This may take a while");
-    let mut output = format!("<svg viewBox=\"0 0 {} {}\" xmlns=\"http://www.w3.org/2000/svg\"><rect x=\"0\" y=\"0\" width=\"100%\" height=\"100%\" fill=\"rgb({}, {}, {})\"/><clipPath id=\"clipView\"><rect x=\"0\" y=\"0\" width=\"{}\" height=\"{}\"/></clipPath><g clip-path=\"url(#clipView)\">", input_image.width(), input_image.height(), avgcolor[0], avgcolor[1], avgcolor[2], input_image.width(), input_image.height());
-    let mut svg_cache: HashMap<PathBuf, String> = HashMap::new();
-    let style_prop_regex = Regex::new(r"(fill|color):.+?;").unwrap();
-    let tag_regex = Regex::new(r#"(?s)(<(style|metadata)\b[^>]*>.*?</(style|metadata)>|<\s*(metadata|g)\b[^>]*\/\s*>|(class|version)\s*=\s*"(.*?)"|(class|version)\s*=\s*'(.*?)'|xmlns(:\w+)?\s*=\s*"[^"]*"|xmlns(:\w+)?\s*=\s*'[^']*')"#).unwrap(); // All style, metadata, and empty g tags, as well as all class tags and xmlns tags
-    let space_regex = Regex::new(r"\s+").unwrap();
-    let none = "none".to_string();
-    for img in placed {
-        if !svg_cache.contains_key(img.src_svg.as_ref()) {
-            let mut svg = Element::parse(fs::read_to_string(img.src_svg.as_ref()).unwrap().as_bytes()).unwrap();
-            svg.name = "symbol".to_string();
-            svg.attributes.insert("id".to_string(), format!("{}", svg_cache.len()));
-            svg.attributes.insert("fill".to_string(), "currentColor".to_string());
-            if svg.attributes.get("stroke").unwrap_or_else(|| &none).to_string() != none { // Some use stroke, we don't like them but have to support it
-                svg.attributes.insert("stroke".to_string(), "currentColor".to_string());
-            } else {
-                svg.attributes.insert("stroke".to_string(), "none".to_string());
+        log!("{} images failed", args.imgcnt);
+        log!("{}/{}/{}/{} (placed/failed/consecutive fails/score){}", success.to_string().bright_green(), failure.to_string().bright_red(), consec_fails.to_string().bright_yellow(), format!("{:.04}", curr_score * 100.0).bright_magenta(), gain_status(placed.len()));
+    }
+
+    if let Some(passes) = args.refine && !placed.is_empty() {
+        let (width, height) = (input_image.width(), input_image.height());
+        let base = blank_image(width, height);
+        let mut objs: Vec<ImageObj> = placed.iter().map(|settings| render_shape(*settings, width)).collect();
+        let mut grid = ShapeGrid::new(width, height, 32);
+        for (i, obj) in objs.iter().enumerate() {
+            grid.insert(i, obj.bounds());
+        }
+        let max_size = if args.allow_oversize { u32::MAX } else { width.min(height) };
+        let nudges = |s: ImageSetting, color_counts: &HashMap<[u8; 3], u32>| -> Vec<ImageSetting> {
+            let step = (s.size / 8).max(1) as i64;
+            let mut out = vec![];
+            for (dx, dy) in [(step, 0), (-step, 0), (0, step), (0, -step)] {
+                let (x, y) = ((s.center_x as i64 + dx).clamp(0, width as i64 - 1) as u32, (s.center_y as i64 + dy).clamp(0, height as i64 - 1) as u32);
+                out.push(ImageSetting { center_x: x, center_y: y, ..s });
             }
-            let mut buffer = Cursor::new(Vec::new());
-            svg.write(&mut buffer);
-            let svgtext = String::from_utf8(buffer.into_inner()).unwrap();
-            let tmp = style_prop_regex.replace_all(svgtext.as_ref(), "fill:currentColor;".to_string()); // Replace other fills, like style tags
-            let outstr = tag_regex.replace_all(tmp.as_ref(), "")
-                .replace("<?xml version=\"1.0\" encoding=\"UTF-8\"?>", ""); // Remove styles unless they are inline
-            let outstr_nospace = space_regex.replace_all(outstr.as_str(), " ");
-            output += "<defs>"; // Defs prevents rendering
-            output += outstr_nospace.as_ref(); // These just cause errors, idk why the xml library includes them by default.
-            output += "</defs>";
-
-            svg_cache.insert(img.src_svg.as_ref().clone(), format!("{}", svg_cache.len()));
-        }
-        let svgid = svg_cache.get(img.src_svg.as_ref()).unwrap();
-        output += format!("<use x=\"0\" y=\"0\" transform=\"translate({} {}) rotate({:.03} {} {})\" width=\"{}\" height=\"{}\" color=\"#{:06X}\" href=\"#{}\" />",
-            img.center_x as i32 - (img.size as f32/2.0) as i32,
-            img.center_y as i32 - (img.size as f32/2.0) as i32,
-            img.rotation as f32 * (180.0/PI),
-            img.size as f32/2.0,
-            img.size as f32/2.0,
-            img.size,
-            img.size,
-            (img.color[0] as u32) << 16 | (img.color[1] as u32) << 8 | img.color[2] as u32,
-            svgid
-        ).as_str();
+            for size in [((s.size as f32 * 1.1).round() as u32).max(s.size + 1), ((s.size as f32 * 0.9).round() as u32).min(s.size.saturating_sub(1))] {
+                out.push(ImageSetting { size: size.clamp(1, max_size), ..s });
+            }
+            for rot in [PI / 36.0, -PI / 36.0].into_iter().filter(|_| !images[s.fragment].rotation_invariant) { // 5 degrees
+                out.push(ImageSetting { rotation: (s.rotation + rot).rem_euclid(PI * 2.0), ..s });
+            }
+            for d in [12, -12] {
+                let color = color::nearest(s.color.map(|c| (c as i32 + d).clamp(0, 255) as u8), &palette);
+                if args.max_per_color.is_none_or(|max| color == s.color || color_counts.get(&color).copied().unwrap_or(0) < max) {
+                    out.push(ImageSetting { color, ..s });
+                }
+            }
+            out.retain(|n| n.center_x != s.center_x || n.center_y != s.center_y || n.size != s.size || n.rotation != s.rotation || n.color != s.color);
+            out
+        };
+
+        for pass in 1..=passes {
+            let pass_start = curr_score;
+            let mut improved = 0;
+            for k in 0..placed.len() {
+                if placed[k].pinned {
+                    continue;
+                }
+                let old_bounds = objs[k].bounds();
+                let evaluate = |(i, settings): (usize, ImageSetting)| -> Option<(ImageObj, f64, usize)> {
+                    if let Some(max) = args.max_bytes { // Nudges can add digits to the <use>
+                        let id = &symbol_bytes[&settings.fragment].0;
+                        let grows = use_tag(&settings, id).len() as i64 - use_tag(&placed[k], id).len() as i64;
+                        if grows > 0 && out_bytes + grows as usize > max {
+                            return None;
+                        }
+                    }
+                    let obj = render_shape(settings, width);
+                    let new_bounds = obj.bounds();
+                    let (left, top) = (old_bounds.0.min(new_bounds.0).max(0), old_bounds.1.min(new_bounds.1).max(0));
+                    let (right, bottom) = (old_bounds.2.max(new_bounds.2).min(width as i64), old_bounds.3.max(new_bounds.3).min(height as i64));
+                    if right <= left || bottom <= top {
+                        return None; // Entirely off canvas either way
+                    }
+                    let mut shapes = grid.query((left, top, right, bottom));
+                    if !shapes.contains(&k) {
+                        shapes.push(k);
+                        shapes.sort_unstable();
+                    }
+                    let mut patch = imageops::crop_imm(&base, left as u32, top as u32, (right - left) as u32, (bottom - top) as u32).to_image();
+                    for j in shapes {
+                        let other = if j == k { &obj } else { &objs[j] };
+                        imageops::overlay(&mut patch, &other.im, other.topleft_x_pos - left, other.topleft_y_pos - top);
+                    }
+                    let mut desttmp = dest_image.clone();
+                    imageops::replace(&mut desttmp, &patch, left, top);
+                    let newscore = metric::quantize(metric::unquantized(&input_image, &desttmp, metric, blend_bg), quantization);
+                    metric.is_improvement(newscore, curr_score).then_some((obj, newscore, i))
+                };
+                let candidates: Vec<(usize, ImageSetting)> = nudges(placed[k], &color_counts).into_iter().enumerate().collect();
+                let results: Vec<Option<(ImageObj, f64, usize)>> = if args.parallel {
+                    candidates.into_par_iter().map(evaluate).collect()
+                } else {
+                    candidates.into_iter().map(evaluate).collect()
+                };
+                if let Some((obj, score, _)) = pick_best(metric, results.into_iter().flatten()) {
+                    if let Some(max) = args.max_bytes.filter(|_| obj.settings.fragment == placed[k].fragment) {
+                        let id = &symbol_bytes[&placed[k].fragment].0;
+                        out_bytes = (out_bytes + use_tag(&obj.settings, id).len()).saturating_sub(use_tag(&placed[k], id).len()).min(max);
+                    }
+                    if obj.settings.color != placed[k].color {
+                        *color_counts.entry(placed[k].color).or_insert(1) -= 1;
+                        *color_counts.entry(obj.settings.color).or_insert(0) += 1;
+                    }
+                    // Redraw the affected region for real, the same way the candidate was scored
+                    let new_bounds = obj.bounds();
+                    let (left, top) = (old_bounds.0.min(new_bounds.0).max(0), old_bounds.1.min(new_bounds.1).max(0));
+                    let (right, bottom) = (old_bounds.2.max(new_bounds.2).min(width as i64), old_bounds.3.max(new_bounds.3).min(height as i64));
+                    grid.remove(k, old_bounds);
+                    grid.insert(k, new_bounds);
+                    placed[k] = obj.settings;
+                    objs[k] = obj;
+                    let mut patch = imageops::crop_imm(&base, left as u32, top as u32, (right - left) as u32, (bottom - top) as u32).to_image();
+                    for j in grid.query((left, top, right, bottom)) {
+                        imageops::overlay(&mut patch, &objs[j].im, objs[j].topleft_x_pos - left, objs[j].topleft_y_pos - top);
+                    }
+                    imageops::replace(&mut dest_image, &patch, left, top);
+                    curr_score = score;
+                    improved += 1;
+                    stream(k, &placed[k], width, curr_score);
+                }
+            }
+            log!("{}", format!("Refine pass {pass}: {improved} of {} shapes improved ({:.04}% -> {:.04}%)", placed.len(), pass_start * 100.0, curr_score * 100.0).bright_cyan());
+            publish(&placed, width, format!("Refine pass {pass}, {:.04}%", curr_score * 100.0));
+            if improved == 0 || stopped() {
+                break;
+            }
+        }
+        coverage = RgbaImage::new(width, height);
+        for obj in &objs {
+            imageops::overlay(&mut coverage, &obj.im, obj.topleft_x_pos, obj.topleft_y_pos);
+        }
+    }
+
+    log!("Image finished!");
+    if args.prefilter.is_some() {
+        let (skipped, checked) = (prefiltered.0.into_inner(), prefiltered.1.into_inner());
+        log!("The prefilter skipped {skipped} of {checked} compares ({:.02}%)", skipped as f64 / checked.max(1) as f64 * 100.0);
+    }
+    if args.restart_budget > 0 {
+        let target_met = !metric.is_improvement(target_score, curr_score) && success >= target_shapes;
+        log!("Used {restarts} of {} restarts, the target was {}", args.restart_budget, if target_met { "met".bright_green() } else { "not met".bright_red() });
+    }
+    if args.adaptive_cmpwidth {
+        log!("Comparison width schedule: {}", width_schedule.iter().map(|(w, shapes, score)| format!("{w}px from {shapes} shapes ({:.04}%)", score * 100.0)).collect::<Vec<_>>().join(" -> "));
     }
-    output += "</g></svg>";
+    if !args.no_background && blur_base.is_none() {
+        let covered = coverage.pixels().filter(|p| p[3] >= 250).count() as f64 / (coverage.width() * coverage.height()) as f64;
+        if covered >= 0.99 {
+            log!("{}", format!("Shapes cover {:.02}% of the canvas, the background is probably unnecessary and can be dropped with --no-background", covered * 100.0).yellow());
+        }
+    }
+    log!("Saving... This may take a while");
+    let output = emit_svg(&placed, input_image.width());
 
-    fs::write(outfile.clone(), output);
-    dest_image.save(outfile + ".png");
+    fs::write(outfile.clone(), &output).unwrap();
+    if let Some(max) = args.max_bytes {
+        let report = format!("Output is {} bytes, {:.02}% of the {max} byte budget", output.len(), output.len() as f64 / max as f64 * 100.0);
+        log!("{}", if output.len() > max { report.red() } else { report.normal() });
+    }
+    dest_image.save(outfile.clone() + ".png").unwrap();
+    if format == OutputFormat::Pdf {
+        let pdffile = outfile.strip_suffix(".svg").unwrap().to_string() + ".pdf";
+        match render::to_pdf(&output) {
+            Ok(pdf) => {
+                fs::write(&pdffile, pdf).unwrap();
+                log!("Saved {pdffile}");
+            },
+            Err(e) => {
+                log!("{}", e.red());
+                exit(1);
+            }
+        }
+    }
+
+    if let Some(path) = &args.error_map {
+        let error = metric::local_error(&input_image, &dest_image, metric, blend_bg);
+        let (min, max) = (error.iter().copied().fold(1.0f32, f32::min), error.iter().copied().fold(0.0f32, f32::max));
+        let mean = error.iter().sum::<f32>() / error.len() as f32;
+        let map = RgbImage::from_fn(input_image.width(), input_image.height(), |x, y| Rgb(color::heat((error[(y * input_image.width() + x) as usize] - min) / (max - min).max(f32::EPSILON))));
+        match map.save(path) {
+            Ok(()) => log!("Saved error map to {} (error {:.04} best, {:.04} mean, {:.04} worst)", path.display(), min, mean, max),
+            Err(e) => log!("{}", format!("Could not write error map {}: {e}", path.display()).red())
+        }
+    }
+
+    if args.verify {
+        let raster = render::without_display_size(&output).and_then(|svg| render::rasterize(&svg, input_image.width(), input_image.height())).unwrap_or_else(|e| {
+            log!("{}", e.red());
+            exit(1);
+        });
+        let svg_score = compare(&input_image, &raster, metric, blend_bg);
+        let diff = (svg_score - curr_score).abs() * 100.0;
+        if diff > args.verify_tolerance {
+            log!("{}", format!("Verify failed: svg scores {:.04}%, internal result scores {:.04}% ({:.04} > {:.04} tolerance)", svg_score * 100.0, curr_score * 100.0, diff, args.verify_tolerance).red());
+            exit(1);
+        }
+        log!("{}", format!("Verify passed: svg scores {:.04}%, internal result scores {:.04}%", svg_score * 100.0, curr_score * 100.0).bright_green());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pick_best_takes_the_earliest_of_tied_candidates() {
+        for metric in [Metric::Hybrid, Metric::Mse] {
+            let tied = [("first", 0.5, 0), ("second", 0.5, 1), ("third", 0.5, 2)];
+            assert_eq!(pick_best(metric, tied).unwrap().0, "first", "{metric:?}");
+        }
+        // A better score after a tie still wins, and a later tie with it doesn't
+        assert_eq!(pick_best(Metric::Hybrid, [("a", 0.5, 0), ("b", 0.5, 1), ("c", 0.6, 2), ("d", 0.6, 3)]).unwrap().0, "c");
+        assert_eq!(pick_best(Metric::Mse, [("a", 0.5, 0), ("b", 0.5, 1), ("c", 0.4, 2), ("d", 0.4, 3)]).unwrap().0, "c");
+        assert!(pick_best::<&str>(Metric::Hybrid, []).is_none());
+    }
 }
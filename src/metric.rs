@@ -0,0 +1,235 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The metrics the search optimizes, all scores are 0.0-1.0. Most are similarities where higher is better but not all, so scores
+//! should only ever be compared through Metric::is_improvement
+
+use clap::ValueEnum;
+use image::{DynamicImage, Rgb, RgbImage, Rgba, RgbaImage};
+use image_compare::{Algorithm, Similarity};
+
+/// Scores are floored to this many steps so tiny floating point noise can't count as an improvement
+pub const QUANTIZATION: f64 = 1000000.0;
+/// How far the search may refine QUANTIZATION once it's what stops shapes from counting as improvements, much finer and the
+/// differences left are rounding
+pub const MAX_QUANTIZATION: f64 = 1000000000.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Metric {
+    /// SSIM on luma and RMS on chroma after blending both images onto the background color, the default
+    Hybrid,
+    /// Like hybrid but compares alpha instead of blending (on premultiplied color), so transparent areas count against the match
+    RgbaHybrid,
+    /// SSIM per RGB channel after blending, cares about structure more than color
+    Ssim,
+    /// RMS error per RGB channel after blending, cares about color more than structure
+    Rms,
+    /// Mean squared error per RGB channel after blending. Lower is better unlike the others, so scores and --matchscore are an error percentage
+    Mse,
+}
+
+impl Metric {
+    pub fn higher_is_better(self) -> bool {
+        self != Metric::Mse
+    }
+
+    /// The worst score this metric can give, anything is at least as good
+    pub fn initial_worst(self) -> f64 {
+        if self.higher_is_better() { 0.0 } else { 1.0 }
+    }
+
+    pub fn is_improvement(self, new: f64, old: f64) -> bool {
+        self.is_improvement_by(new, old, 0.0)
+    }
+
+    /// Whether new is better than old by more than margin
+    pub fn is_improvement_by(self, new: f64, old: f64, margin: f64) -> bool {
+        if self.higher_is_better() { new > old + margin } else { new < old - margin }
+    }
+}
+
+fn blend(im: &RgbaImage, bg: Rgb<u8>) -> RgbImage {
+    RgbImage::from_fn(im.width(), im.height(), |x, y| {
+        let px = im.get_pixel(x, y);
+        let a = px[3] as f32 / 255.0;
+        Rgb([0, 1, 2].map(|c| (px[c] as f32 * a + bg[c] as f32 * (1.0 - a)).round() as u8))
+    })
+}
+
+/// Mean of the squared channel differences of every pixel, 0.0-1.0
+fn squared_error(a: &RgbaImage, b: &RgbaImage, bg: Rgb<u8>) -> Vec<f32> {
+    assert_eq!(a.dimensions(), b.dimensions(), "Compared images must be the same size");
+    blend(a, bg).pixels().zip(blend(b, bg).pixels()).map(|(pa, pb)| {
+        (0..3).map(|c| ((pa[c] as f32 - pb[c] as f32) / 255.0).powi(2)).sum::<f32>() / 3.0
+    }).collect()
+}
+
+/// Color scaled by alpha, so what a fully transparent pixel's color channels hold (nothing, once rendered) can't count
+fn premultiply(im: &RgbaImage) -> RgbaImage {
+    RgbaImage::from_fn(im.width(), im.height(), |x, y| {
+        let px = im.get_pixel(x, y);
+        let a = px[3] as u32;
+        Rgba([(px[0] as u32 * a + 127) / 255, (px[1] as u32 * a + 127) / 255, (px[2] as u32 * a + 127) / 255, a].map(|c| c as u8))
+    })
+}
+
+fn similarity(a: &RgbaImage, b: &RgbaImage, metric: Metric, bg: Rgb<u8>) -> Similarity {
+    match metric {
+        Metric::Mse => unreachable!("Mse isn't an image-compare similarity"),
+        Metric::Hybrid => image_compare::rgba_blended_hybrid_compare(a.into(), b.into(), bg),
+        Metric::RgbaHybrid => image_compare::rgba_hybrid_compare(&premultiply(a), &premultiply(b)),
+        Metric::Ssim => image_compare::rgb_similarity_structure(&Algorithm::MSSIMSimple, &blend(a, bg), &blend(b, bg)),
+        Metric::Rms => image_compare::rgb_similarity_structure(&Algorithm::RootMeanSquared, &blend(a, bg), &blend(b, bg)),
+    }.expect("Compared images must be the same size")
+}
+
+/// Scores how similar two equally sized images are, exactly how the search scores candidates. bg is what
+/// transparent areas are blended onto for every metric except RgbaHybrid
+pub fn compare(a: &RgbaImage, b: &RgbaImage, metric: Metric, bg: Rgb<u8>) -> f64 {
+    quantize(unquantized(a, b, metric, bg), QUANTIZATION)
+}
+
+/// compare without the flooring, for telling whether flooring is what made two scores equal
+pub fn unquantized(a: &RgbaImage, b: &RgbaImage, metric: Metric, bg: Rgb<u8>) -> f64 {
+    match metric {
+        Metric::Mse => {
+            let error = squared_error(a, b, bg);
+            error.iter().map(|e| *e as f64).sum::<f64>() / error.len().max(1) as f64
+        },
+        _ => similarity(a, b, metric, bg).score
+    }
+}
+
+/// Floors a score to this many steps, QUANTIZATION is what compare uses
+pub fn quantize(score: f64, quantization: f64) -> f64 {
+    (score * quantization).floor() / quantization
+}
+
+/// Per pixel error behind the score, row major, 0.0 is identical and 1.0 is as different as it gets. Channels are averaged
+pub fn local_error(a: &RgbaImage, b: &RgbaImage, metric: Metric, bg: Rgb<u8>) -> Vec<f32> {
+    if metric == Metric::Mse {
+        return squared_error(a, b, bg);
+    }
+    let inverted = matches!(metric, Metric::Ssim | Metric::Rms); // Those report similarity per pixel, the hybrids report difference
+    let mean = |channels: &[u8]| channels.iter().map(|c| *c as f32).sum::<f32>() / channels.len() as f32 / 255.0;
+    let values: Vec<f32> = match similarity(a, b, metric, bg).image.to_color_map() { // The f32 image itself isn't reachable, this is it clamped to 0-255
+        DynamicImage::ImageLuma8(im) => im.pixels().map(|p| mean(&p.0)).collect(),
+        DynamicImage::ImageRgba8(im) => im.pixels().map(|p| mean(&p.0)).collect(),
+        other => other.to_rgb8().pixels().map(|p| mean(&p.0)).collect(),
+    };
+    values.into_iter().map(|v| if inverted { 1.0 - v } else { v }).collect()
+}
+
+/// Mean absolute error of each RGB channel over the region (left, top, right, bottom), clipped to the images, after blending onto bg
+/// like the blended metrics. 0.0-1.0, zeros for an empty region
+pub fn channel_errors(a: &RgbaImage, b: &RgbaImage, bg: Rgb<u8>, region: (i64, i64, i64, i64)) -> [f64; 3] {
+    let (left, top) = (region.0.max(0) as u32, region.1.max(0) as u32);
+    let (right, bottom) = (region.2.min(a.width() as i64).max(0) as u32, region.3.min(a.height() as i64).max(0) as u32);
+    let blend_px = |px: &image::Rgba<u8>, c: usize| {
+        let alpha = px[3] as f64 / 255.0;
+        px[c] as f64 * alpha + bg[c] as f64 * (1.0 - alpha)
+    };
+    let mut sums = [0.0; 3];
+    for y in top..bottom {
+        for x in left..right {
+            let (pa, pb) = (a.get_pixel(x, y), b.get_pixel(x, y));
+            for (c, sum) in sums.iter_mut().enumerate() {
+                *sum += (blend_px(pa, c) - blend_px(pb, c)).abs();
+            }
+        }
+    }
+    let count = (right.saturating_sub(left) as f64 * bottom.saturating_sub(top) as f64).max(1.0);
+    sums.map(|sum| sum / count / 255.0)
+}
+
+/// How much closer to `target` overlaying `shape` at (x, y) brings `canvas`, as the drop in mean absolute RGB error over the shape's
+/// bounding box (clipped to the images) after blending onto bg. 0.0-1.0 range, negative when it makes things worse. Integer math only,
+/// it's meant as a cheap guess at whether the real score is worth working out
+pub fn overlay_gain(target: &RgbaImage, canvas: &RgbaImage, shape: &RgbaImage, x: i64, y: i64, bg: Rgb<u8>) -> f64 {
+    let (left, top) = (x.max(0), y.max(0));
+    let (right, bottom) = ((x + shape.width() as i64).min(target.width() as i64), (y + shape.height() as i64).min(target.height() as i64));
+    if right <= left || bottom <= top {
+        return 0.0;
+    }
+    let blend_px = |c: u32, alpha: u32, under: u32| (c * alpha + under * (255 - alpha) + 127) / 255;
+    let mut gain: i64 = 0;
+    for py in top..bottom {
+        for px in left..right {
+            let (t, d, s) = (target.get_pixel(px as u32, py as u32), canvas.get_pixel(px as u32, py as u32), shape.get_pixel((px - x) as u32, (py - y) as u32));
+            for c in 0..3 {
+                let want = blend_px(t[c] as u32, t[3] as u32, bg[c] as u32) as i64;
+                let before = blend_px(d[c] as u32, d[3] as u32, bg[c] as u32);
+                let after = blend_px(s[c] as u32, s[3] as u32, before) as i64; // Straight over, the same as imageops::overlay once blended
+                gain += (want - before as i64).abs() - (want - after).abs();
+            }
+        }
+    }
+    gain as f64 / ((right - left) * (bottom - top) * 3) as f64 / 255.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [Metric; 5] = [Metric::Hybrid, Metric::RgbaHybrid, Metric::Ssim, Metric::Rms, Metric::Mse];
+
+    fn pattern() -> RgbaImage {
+        RgbaImage::from_fn(32, 24, |x, y| Rgba([(x * 8) as u8, (y * 10) as u8, ((x * y) % 256) as u8, if (x + y) % 5 == 0 { 128 } else { 255 }]))
+    }
+
+    #[test]
+    fn improvements_follow_the_metric_direction() {
+        assert!(Metric::Hybrid.is_improvement(0.6, 0.5));
+        assert!(!Metric::Hybrid.is_improvement(0.4, 0.5));
+        assert!(!Metric::Hybrid.is_improvement(0.5, 0.5));
+        assert!(Metric::Mse.is_improvement(0.4, 0.5));
+        assert!(!Metric::Mse.is_improvement(0.6, 0.5));
+        assert!(!Metric::Mse.is_improvement(0.5, 0.5));
+    }
+
+    #[test]
+    fn improvements_must_beat_the_margin() {
+        assert!(Metric::Hybrid.is_improvement_by(0.6, 0.5, 0.05));
+        assert!(!Metric::Hybrid.is_improvement_by(0.52, 0.5, 0.05));
+        assert!(Metric::Mse.is_improvement_by(0.4, 0.5, 0.05));
+        assert!(!Metric::Mse.is_improvement_by(0.48, 0.5, 0.05));
+    }
+
+    #[test]
+    fn anything_beats_the_initial_worst() {
+        assert_eq!(Metric::Hybrid.initial_worst(), 0.0);
+        assert_eq!(Metric::Mse.initial_worst(), 1.0);
+        for metric in ALL {
+            for score in [0.001, 0.5, 0.999] {
+                assert!(metric.is_improvement(score, metric.initial_worst()), "{metric:?} doesn't take {score} over the initial worst");
+            }
+        }
+    }
+
+    #[test]
+    fn identical_images_score_perfectly() {
+        let im = pattern();
+        for metric in ALL {
+            let expected = if metric.higher_is_better() { 1.0 } else { 0.0 };
+            assert_eq!(compare(&im, &im, metric, Rgb([40, 80, 120])), expected, "{metric:?}");
+        }
+    }
+
+    #[test]
+    fn rgba_hybrid_ignores_the_color_of_transparent_pixels() {
+        let mut a = pattern();
+        let mut b = pattern();
+        for (x, y) in [(0, 0), (10, 4), (31, 23)] {
+            a.put_pixel(x, y, Rgba([255, 0, 255, 0]));
+            b.put_pixel(x, y, Rgba([0, 0, 0, 0]));
+        }
+        assert_eq!(compare(&a, &b, Metric::RgbaHybrid, Rgb([0, 0, 0])), 1.0);
+    }
+
+    #[test]
+    fn quantize_floors() {
+        assert_eq!(quantize(0.1234567, 1000.0), 0.123);
+        assert_eq!(quantize(0.9999999, QUANTIZATION), 0.999999);
+    }
+}
@@ -0,0 +1,87 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A tiny local web server showing the latest SVG of a run as it's built, with a button to stop the run early
+
+use std::{sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, thread};
+
+use tiny_http::{Header, Method, Response, Server};
+
+const PAGE: &str = r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><title>SVGIZE preview</title><style>
+body { margin: 0; font-family: sans-serif; background: #222; color: #ddd }
+#bar { padding: 8px } #view { height: calc(100vh - 48px) } #view svg { width: 100%; height: 100% }
+</style></head><body>
+<div id="bar"><button id="stop">Stop and write the output</button> <span id="status">Waiting for the first shape...</span></div>
+<div id="view"></div>
+<script>
+let last = "";
+const status = document.getElementById("status");
+async function poll() {
+    try {
+        const res = await fetch("/svg");
+        const svg = await res.text();
+        if (svg && svg !== last) {
+            document.getElementById("view").innerHTML = svg;
+            last = svg;
+        }
+        status.textContent = res.headers.get("X-Status");
+    } catch {
+        status.textContent = "The run has ended";
+        return;
+    }
+    setTimeout(poll, 1000);
+}
+document.getElementById("stop").onclick = () => fetch("/stop", { method: "POST" });
+poll();
+</script></body></html>"#;
+
+/// What the run shares with the server thread
+#[derive(Default)]
+pub struct Preview {
+    svg: Mutex<(String, String)>, // (svg, status line)
+    stop: AtomicBool
+}
+
+impl Preview {
+    /// Replaces what the page shows
+    pub fn publish(&self, svg: String, status: String) {
+        let mut shown = self.svg.lock().unwrap();
+        shown.0 = svg;
+        if !self.stopped() { // Keep saying it's stopping
+            shown.1 = status;
+        }
+    }
+
+    /// Whether the stop button was pressed
+    pub fn stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+}
+
+/// Starts serving on localhost in a background thread, which lives until the process exits
+pub fn serve(port: u16) -> Result<Arc<Preview>, String> {
+    let server = Server::http(("127.0.0.1", port)).map_err(|e| format!("Could not serve on port {port}: {e}"))?;
+    let preview = Arc::new(Preview::default());
+    let shared = preview.clone();
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let response = match (request.method(), request.url()) {
+                (Method::Get, "/") => Response::from_string(PAGE).with_header(Header::from_bytes("Content-Type", "text/html; charset=utf-8").unwrap()),
+                (Method::Get, "/svg") => {
+                    let (svg, status) = shared.svg.lock().unwrap().clone();
+                    Response::from_string(svg).with_header(Header::from_bytes("Content-Type", "image/svg+xml").unwrap()).with_header(Header::from_bytes("X-Status", status).unwrap())
+                },
+                (Method::Post, "/stop") => {
+                    shared.stop.store(true, Ordering::Relaxed);
+                    shared.svg.lock().unwrap().1 = "Stopping, the output is being written".to_string();
+                    Response::from_string("")
+                },
+                _ => Response::from_string("Not found").with_status_code(404)
+            };
+            let _ = request.respond(response); // The page going away mid response isn't our problem
+        }
+    });
+    Ok(preview)
+}
@@ -0,0 +1,58 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Rendering the emitted SVG, rasterized with resvg to check it against what the program scored internally or converted to PDF with svg2pdf
+
+use std::io::Cursor;
+
+use image::RgbaImage;
+use resvg::{tiny_skia, usvg};
+use xmltree::{Element, EmitterConfig};
+
+fn parse(svg: &str, style_sheet: Option<&str>) -> Result<usvg::Tree, String> {
+    let mut options = usvg::Options {
+        style_sheet: style_sheet.map(str::to_string),
+        ..Default::default()
+    };
+    options.fontdb_mut().load_system_fonts(); // Fragments with text would otherwise silently lose it
+    usvg::Tree::from_str(svg, &options).map_err(|e| format!("Could not parse svg: {e}"))
+}
+
+/// Renders the svg stretched to exactly width x height, so the result lines up pixel for pixel with the comparison image
+pub fn rasterize(svg: &str, width: u32, height: u32) -> Result<RgbaImage, String> {
+    rasterize_styled(svg, width, height, None)
+}
+
+/// Like rasterize but with extra CSS applied on top of the svg's own styles
+pub fn rasterize_styled(svg: &str, width: u32, height: u32, style_sheet: Option<&str>) -> Result<RgbaImage, String> {
+    let tree = parse(svg, style_sheet)?;
+    let mut pixmap = tiny_skia::Pixmap::new(width, height).ok_or_else(|| format!("Can't rasterize to a {width}x{height} image"))?;
+    let size = tree.size();
+    resvg::render(&tree, tiny_skia::Transform::from_scale(width as f32 / size.width(), height as f32 / size.height()), &mut pixmap.as_mut());
+
+    let mut out = RgbaImage::new(width, height);
+    for (dst, src) in out.pixels_mut().zip(pixmap.pixels()) {
+        let c = src.demultiply(); // tiny-skia stores premultiplied alpha, image expects straight
+        *dst = image::Rgba([c.red(), c.green(), c.blue(), c.alpha()]);
+    }
+    Ok(out)
+}
+
+/// The svg without the root's width, height and preserveAspectRatio, so it rasterizes to exactly its viewBox. Checks need that, a display box of
+/// another aspect ratio would crop or letterbox it
+pub fn without_display_size(svg: &str) -> Result<String, String> {
+    let mut root = Element::parse(svg.as_bytes()).map_err(|e| format!("Could not parse svg: {e}"))?;
+    for name in ["width", "height", "preserveAspectRatio"] {
+        root.attributes.shift_remove(name);
+    }
+    let mut buffer = Cursor::new(Vec::new());
+    root.write_with_config(&mut buffer, EmitterConfig::new()).map_err(|e| format!("Could not write svg: {e}"))?;
+    String::from_utf8(buffer.into_inner()).map_err(|e| format!("Could not write svg: {e}"))
+}
+
+/// Converts the svg to a single page PDF, shapes stay vectors
+pub fn to_pdf(svg: &str) -> Result<Vec<u8>, String> {
+    let tree = parse(svg, None)?;
+    svg2pdf::to_pdf(&tree, svg2pdf::ConversionOptions::default(), svg2pdf::PageOptions::default()).map_err(|e| format!("Could not convert to pdf: {e}"))
+}
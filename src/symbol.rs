@@ -0,0 +1,251 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Turning fragment SVGs into `<symbol>`s that can all live in one document and take their color from the `<use>` that places them
+
+use std::{collections::HashSet, io::Cursor};
+
+use xmltree::{Element, EmitterConfig, XMLNode};
+
+const SECONDARY_CLASSES: [&str; 2] = ["secondary", "fa-secondary"]; // fa- is what font awesome duotone icons use
+const SHAPE_TAGS: [&str; 9] = ["path", "rect", "circle", "ellipse", "line", "polyline", "polygon", "text", "use"];
+const DROPPED_TAGS: [&str; 2] = ["style", "metadata"]; // Stylesheets would apply to the whole document, not just this symbol
+const STENCIL_TAGS: [&str; 3] = ["mask", "pattern", "marker"]; // Their content's colors are what they do (mask luminance, a tile), never recolored
+
+const KEEP_COLOR_CLASS: &str = "keep-color";
+const KEEP_COLOR_ATTR: &str = "data-keep-color";
+
+/// Hides everything but the secondary parts of a duotone fragment, used to render its secondary mask
+pub const SECONDARY_ONLY_CSS: &str = "* { fill-opacity: 0; stroke-opacity: 0 } .secondary, .secondary *, .fa-secondary, .fa-secondary * { fill-opacity: 1; stroke-opacity: 1 }";
+/// Hides everything but the parts that keep their own color, used to render them for scoring
+pub const KEEP_COLOR_ONLY_CSS: &str = "* { fill-opacity: 0; stroke-opacity: 0 } .keep-color, .keep-color *, [data-keep-color], [data-keep-color] * { fill-opacity: 1; stroke-opacity: 1 }";
+
+fn is_secondary(el: &Element) -> bool {
+    el.attributes.get("class").is_some_and(|c| c.split_whitespace().any(|c| SECONDARY_CLASSES.contains(&c)))
+}
+
+fn has_secondary(el: &Element) -> bool {
+    is_secondary(el) || el.children.iter().filter_map(|c| c.as_element()).any(has_secondary)
+}
+
+/// Accents like a red dot that designers marked with the keep-color class or a data-keep-color attribute, these and everything in them
+/// are never recolored
+fn keeps_color(el: &Element) -> bool {
+    el.attributes.contains_key(KEEP_COLOR_ATTR) || el.attributes.get("class").is_some_and(|c| c.split_whitespace().any(|c| c == KEEP_COLOR_CLASS))
+}
+
+fn has_kept_color(el: &Element) -> bool {
+    keeps_color(el) || el.children.iter().filter_map(|c| c.as_element()).any(has_kept_color)
+}
+
+/// Whether part of the fragment keeps its own color instead of taking the <use>'s
+pub fn has_kept_colors(svg: &str) -> bool {
+    svg.contains(KEEP_COLOR_CLASS) && Element::parse(svg.as_bytes()).is_ok_and(|el| has_kept_color(&el))
+}
+
+/// Whether the fragment marks parts of itself as secondary, so it takes two colors
+pub fn is_duotone(svg: &str) -> bool {
+    svg.contains("secondary") && Element::parse(svg.as_bytes()).is_ok_and(|el| has_secondary(&el))
+}
+
+/// Paints that aren't a color and have to survive recoloring, otherwise gradients and see-through parts get flattened
+fn keeps_paint(value: &str) -> bool {
+    let value = value.trim();
+    value == "none" || value.starts_with("url(")
+}
+
+/// The fill the element sets itself, a style declaration wins over the attribute like in CSS. None if it inherits
+fn own_fill(el: &Element) -> Option<String> {
+    let from_style = el.attributes.get("style").and_then(|style| style.split(';').filter_map(|decl| decl.split_once(':'))
+        .rev().find(|(prop, _)| prop.trim() == "fill").map(|(_, value)| value.trim().to_string()));
+    from_style.or_else(|| el.attributes.get("fill").map(|f| f.trim().to_string())).filter(|f| f != "inherit")
+}
+
+/// Every color becomes currentColor (the <use>'s color). For duotone fragments that's only the primary shapes, everything else loses its fill
+/// so secondary shapes inherit the <use>'s fill. inherited_fill is what the element would inherit in the original fragment
+fn recolor(el: &mut Element, duotone: bool, in_secondary: bool, inherited_fill: &str) {
+    let own = own_fill(el);
+    if keeps_color(el) {
+        if own.is_none() { // Its ancestors are about to be recolored, so what it inherited has to be spelled out
+            el.attributes.insert("fill".to_string(), inherited_fill.to_string());
+        }
+        return;
+    }
+    let inherited_fill = own.unwrap_or_else(|| inherited_fill.to_string()); // Read before this element's own fill is rewritten
+    let secondary = in_secondary || (duotone && is_secondary(el));
+    let fill = (!duotone || (!secondary && SHAPE_TAGS.contains(&el.name.as_str()))).then_some("currentColor"); // None inherits
+
+    if let Some(style) = el.attributes.get_mut("style") {
+        *style = style.split(';').filter_map(|decl| {
+            let (prop, value) = decl.split_once(':')?;
+            match prop.trim() {
+                "fill" if !keeps_paint(value) => fill.map(|f| format!("fill:{f}")),
+                "stroke" if !keeps_paint(value) => Some("stroke:currentColor".to_string()),
+                "color" => None,
+                _ => Some(decl.trim().to_string())
+            }
+        }).collect::<Vec<_>>().join(";");
+        if style.is_empty() {
+            el.attributes.shift_remove("style");
+        }
+    }
+    match (el.attributes.get("fill").map(|f| keeps_paint(f)), fill) {
+        (Some(true), _) => {},
+        (_, None) => {
+            el.attributes.shift_remove("fill");
+        },
+        (Some(false), Some(f)) => {
+            el.attributes.insert("fill".to_string(), f.to_string());
+        },
+        (None, Some(f)) => if duotone { // Otherwise the symbol's own fill is inherited
+            el.attributes.insert("fill".to_string(), f.to_string());
+        }
+    }
+    if el.attributes.get("stroke").is_some_and(|s| !keeps_paint(s)) {
+        el.attributes.insert("stroke".to_string(), "currentColor".to_string());
+    }
+    el.attributes.shift_remove("color");
+
+    for child in el.children.iter_mut().filter_map(|c| c.as_mut_element()).filter(|c| !STENCIL_TAGS.contains(&c.name.as_str())) {
+        recolor(child, duotone, secondary, &inherited_fill);
+    }
+}
+
+fn collect_ids(el: &Element, ids: &mut HashSet<String>) {
+    if let Some(id) = el.attributes.get("id") {
+        ids.insert(id.clone());
+    }
+    for child in el.children.iter().filter_map(|c| c.as_element()) {
+        collect_ids(child, ids);
+    }
+}
+
+/// Prefixes the target of every `url(#id)` (quoted or not) that points at an id inside this fragment, anything else is left as is
+fn prefix_urls(value: &str, prefix: &str, ids: &HashSet<String>) -> String {
+    let mut out = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("url(") {
+        let (before, after) = rest.split_at(start + 4);
+        let end = after.find(')').unwrap_or(after.len());
+        out += before;
+        match after[..end].trim().trim_matches(['\'', '"']).strip_prefix('#').filter(|id| ids.contains(*id)) {
+            Some(id) => out += &format!("#{prefix}{id}"),
+            None => out += &after[..end]
+        }
+        rest = &after[end..];
+    }
+    out + rest
+}
+
+/// Strips what would leak into or clash with the rest of the document and gives every internal id the symbol's prefix
+fn clean(el: &mut Element, prefix: &str, ids: &HashSet<String>) {
+    el.prefix = None; // The document declares the svg namespace once
+    el.namespace = None;
+    el.namespaces = None;
+    el.attributes.retain(|name, _| name != "class" && name != "version" && name != KEEP_COLOR_ATTR);
+    for (name, value) in el.attributes.iter_mut() {
+        *value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+        if name == "id" {
+            *value = format!("{prefix}{value}");
+        } else if name == "href" { // xlink:href too, the prefix is dropped on parse
+            if let Some(id) = value.strip_prefix('#').filter(|id| ids.contains(*id)) {
+                *value = format!("#{prefix}{id}");
+            }
+        } else if value.contains("url(") {
+            *value = prefix_urls(value, prefix, ids);
+        }
+    }
+
+    el.children.retain_mut(|child| match child {
+        XMLNode::Element(child) => {
+            if DROPPED_TAGS.contains(&child.name.as_str()) {
+                return false;
+            }
+            clean(child, prefix, ids);
+            child.name != "g" || !child.children.is_empty()
+        },
+        XMLNode::Text(text) | XMLNode::CData(text) => {
+            *text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+            !text.is_empty()
+        },
+        _ => false // Comments and processing instructions
+    });
+}
+
+/// Converts a fragment svg to a `<symbol>` with the given id, internal ids are prefixed with it so fragments can't clash
+pub fn to_symbol(svg: &str, id: &str, duotone: bool) -> Result<String, String> {
+    let mut svg = Element::parse(svg.as_bytes()).map_err(|e| format!("Could not parse fragment: {e}"))?;
+    recolor(&mut svg, duotone, false, "black"); // The initial fill
+    let mut ids = HashSet::new();
+    collect_ids(&svg, &mut ids);
+    clean(&mut svg, &format!("{id}-"), &ids);
+
+    svg.name = "symbol".to_string();
+    svg.attributes.insert("id".to_string(), id.to_string());
+    if !duotone {
+        svg.attributes.insert("fill".to_string(), "currentColor".to_string());
+    }
+    if svg.attributes.get("stroke").is_some_and(|s| s != "none") { // Some use stroke, we don't like them but have to support it
+        svg.attributes.insert("stroke".to_string(), "currentColor".to_string());
+    } else {
+        svg.attributes.insert("stroke".to_string(), "none".to_string());
+    }
+
+    let mut buffer = Cursor::new(Vec::new());
+    svg.write_with_config(&mut buffer, EmitterConfig::new().write_document_declaration(false)).map_err(|e| format!("Could not write symbol: {e}"))?; // The declaration would end up mid document
+    String::from_utf8(buffer.into_inner()).map_err(|e| format!("Could not write symbol: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Both define id="a", the left one clips to its left half through it and the right one draws its right half with it
+    const LEFT: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 10 10"><defs><clipPath id="a"><rect width="5" height="10"/></clipPath></defs><rect width="10" height="10" clip-path="url(#a)"/><rect width="10" height="10" clip-path="url('#a')"/></svg>"##;
+    const RIGHT: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" viewBox="0 0 10 10"><defs><rect id="a" x="5" width="5" height="10"/></defs><use xlink:href="#a"/></svg>"##;
+
+    #[test]
+    fn shared_ids_get_the_symbols_prefix() {
+        let left = to_symbol(LEFT, "s0", false).unwrap();
+        let right = to_symbol(RIGHT, "s1", false).unwrap();
+        assert!(left.contains("id=\"s0-a\""), "{left}");
+        assert_eq!(left.matches("url(#s0-a)").count(), 2, "{left}");
+        assert!(!left.contains("#a") && !left.contains("s1-"), "{left}");
+        assert!(right.contains("id=\"s1-a\"") && right.contains("href=\"#s1-a\""), "{right}");
+        assert!(!right.contains("#a") && !right.contains("s0-"), "{right}");
+    }
+
+    #[test]
+    fn kept_colors_survive_when_inherited() {
+        let inherited = r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 10 10"><g fill="#f00"><circle cx="5" cy="5" r="4" class="keep-color"/></g></svg>"##;
+        let symbol = to_symbol(inherited, "s0", false).unwrap();
+        assert!(symbol.contains("<circle cx=\"5\" cy=\"5\" r=\"4\" fill=\"#f00\""), "{symbol}");
+        let default = to_symbol(r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 10 10"><circle r="4" data-keep-color=""/></svg>"#, "s1", false).unwrap();
+        assert!(default.contains("<circle r=\"4\" fill=\"black\""), "{default}");
+
+        let svg = format!("<svg viewBox=\"0 0 10 10\" xmlns=\"http://www.w3.org/2000/svg\"><defs>{symbol}</defs><use href=\"#s0\" width=\"10\" height=\"10\" color=\"#0000FF\"/></svg>");
+        assert_eq!(crate::render::rasterize(&svg, 10, 10).unwrap().get_pixel(5, 5).0, [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn mask_content_keeps_its_colors() {
+        // The mask hides the left half, recolored to currentColor its luminance would depend on the <use>'s color
+        let masked = r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 10 10"><defs><mask id="m"><rect width="10" height="10" fill="white"/><rect width="5" height="10" fill="black"/></mask></defs><rect width="10" height="10" mask="url(#m)"/></svg>"##;
+        let svg = format!("<svg viewBox=\"0 0 10 10\" xmlns=\"http://www.w3.org/2000/svg\"><defs>{}</defs><use href=\"#s0\" width=\"10\" height=\"10\" color=\"#FF0000\"/></svg>",
+            to_symbol(masked, "s0", false).unwrap());
+        let im = crate::render::rasterize(&svg, 10, 10).unwrap();
+        assert_eq!(im.get_pixel(2, 5)[3], 0);
+        assert_eq!(im.get_pixel(7, 5).0, [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn symbols_with_shared_ids_render_independently() {
+        let svg = format!("<svg viewBox=\"0 0 10 10\" xmlns=\"http://www.w3.org/2000/svg\"><defs>{}</defs><defs>{}</defs>\
+            <use href=\"#s0\" width=\"10\" height=\"10\" color=\"#FF0000\"/><use href=\"#s1\" width=\"10\" height=\"10\" color=\"#0000FF\"/></svg>",
+            to_symbol(LEFT, "s0", false).unwrap(), to_symbol(RIGHT, "s1", false).unwrap());
+        let im = crate::render::rasterize(&svg, 10, 10).unwrap();
+        assert_eq!(im.get_pixel(2, 5).0, [255, 0, 0, 255]);
+        assert_eq!(im.get_pixel(7, 5).0, [0, 0, 255, 255]);
+    }
+}
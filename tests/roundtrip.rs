@@ -0,0 +1,181 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Runs the whole pipeline on a tiny committed input with two fixture fragments, then renders the SVG it wrote and checks it scores
+//! what the run scored internally. Catches the SVG and the internal raster drifting apart (rotation centers, the viewBox, clipping, colors)
+
+use std::{fs, path::{Path, PathBuf}, process::Command};
+
+use image::{imageops::{resize, FilterType}, Rgb};
+use image_evo_filter::{color, compare, render, Metric};
+
+/// The default --verify-tolerance, how far apart the rendered SVG and the internal score may be in match percentage
+const TOLERANCE: f64 = 2.0;
+/// How far the luminance orders of --sort-emit drift, past TOLERANCE. The worst on the fixture is 5.02 (seed 4, luminance-reverse)
+const LUMINANCE_TOLERANCE: f64 = 5.5;
+
+fn fixtures() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+}
+
+struct Run {
+    path: PathBuf,
+    svg: String,
+    /// The internal score in percent after the last streamed shape, None if no shape was placed
+    score: Option<f64>,
+    log: String,
+}
+
+/// Runs the program from the fixtures folder (so it finds images/ and images_png/ there) with --stream-jsonl for the scores
+fn run(name: &str, input: &str, args: &[&str]) -> Run {
+    let out = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("{name}.svg"));
+    let output = Command::new(env!("CARGO_BIN_EXE_image_evo_filter")).current_dir(fixtures())
+        .arg(input).arg("-o").arg(&out).arg("--stream-jsonl").args(args)
+        .output().unwrap();
+    let log = String::from_utf8_lossy(&output.stderr).into_owned();
+    assert!(output.status.success(), "{name} failed:\n{log}");
+    let score = String::from_utf8_lossy(&output.stdout).lines().last()
+        .map(|line| line.rsplit_once("\"score\":").unwrap().1.trim_end_matches('}').parse().unwrap());
+    Run { svg: fs::read_to_string(&out).unwrap(), path: out, score, log }
+}
+
+/// Scores the rendered svg against the input the way the run does: resized to cmpwidth, blended onto its average color
+fn rendered_score(svg: &str, input: &str, cmpwidth: u32, metric: Metric) -> f64 {
+    let source = image::open(fixtures().join(input)).unwrap().to_rgba8();
+    let height = (cmpwidth as f32 / source.width() as f32 * source.height() as f32) as u32;
+    let input_image = resize(&source, cmpwidth, height, FilterType::Triangle);
+    let avg = *resize(&input_image, 1, 1, FilterType::Triangle).get_pixel(0, 0);
+    let raster = render::without_display_size(svg).and_then(|svg| render::rasterize(&svg, cmpwidth, height)).unwrap();
+    compare(&input_image, &raster, metric, Rgb([avg[0], avg[1], avg[2]])) * 100.0
+}
+
+/// The attributes of every <use> in the svg, in document order
+fn uses(svg: &str) -> Vec<&str> {
+    svg.split("<use").skip(1).map(|tag| &tag[..tag.find("/>").unwrap()]).collect()
+}
+
+fn attr<'a>(tag: &'a str, name: &str) -> &'a str {
+    let start = tag.find(&format!(" {name}=\"")).unwrap() + name.len() + 3;
+    &tag[start..start + tag[start..].find('"').unwrap()]
+}
+
+fn assert_round_trips(run: &Run, input: &str, cmpwidth: u32, metric: Metric) {
+    assert_round_trips_within(run, input, cmpwidth, metric, TOLERANCE);
+}
+
+fn assert_round_trips_within(run: &Run, input: &str, cmpwidth: u32, metric: Metric, tolerance: f64) {
+    let internal = run.score.expect("no shapes were placed");
+    let rendered = rendered_score(&run.svg, input, cmpwidth, metric);
+    assert!((rendered - internal).abs() <= tolerance, "the svg scores {rendered:.04}%, the run scored {internal:.04}%\n{}", run.svg);
+}
+
+#[test]
+fn svg_scores_like_the_internal_result() {
+    for seed in ["3", "5", "6"] {
+        let run = run(&format!("roundtrip-{seed}"), "input.png", &["-c", "64", "-s", "16", "--seed", seed]);
+        assert_eq!(run.svg.matches("<use").count(), 16);
+        assert_round_trips(&run, "input.png", 64, Metric::Hybrid);
+    }
+}
+
+#[test]
+fn no_background_svg_scores_like_the_internal_result() {
+    for seed in ["1", "2", "3"] {
+        let run = run(&format!("roundtrip-no-background-{seed}"), "input.png", &["-c", "64", "-s", "16", "--seed", seed, "--no-background"]);
+        assert!(!run.svg.contains("width=\"100%\""), "{}", run.svg); // No background rect
+        assert_round_trips(&run, "input.png", 64, Metric::RgbaHybrid);
+    }
+}
+
+#[test]
+fn size_sorted_svg_scores_like_the_internal_result() {
+    for (seed, flag) in [("2", "--reorder-emit"), ("3", "--sort-emit=size"), ("4", "--sort-emit=size")] {
+        let run = run(&format!("roundtrip-size-{seed}"), "input.png", &["-c", "64", "-s", "16", "--seed", seed, flag]);
+        let sizes: Vec<f32> = uses(&run.svg).iter().map(|tag| attr(tag, "width").parse().unwrap()).collect();
+        assert_eq!(sizes.len(), 16);
+        assert!(sizes.is_sorted_by(|a, b| a >= b), "not largest first: {sizes:?}");
+        assert_round_trips(&run, "input.png", 64, Metric::Hybrid);
+    }
+}
+
+#[test]
+fn luminance_sorted_svg_keeps_every_shape_and_its_drift_bound() {
+    let lightness = |tag: &str| {
+        let hex = u32::from_str_radix(attr(tag, "color").trim_start_matches('#'), 16).unwrap();
+        color::rgb_to_lab([(hex >> 16) as u8, (hex >> 8) as u8, hex as u8]).l
+    };
+    let placed = run("roundtrip-luminance-placed", "input.png", &["-c", "64", "-s", "16", "--seed", "1"]);
+    let mut placed_uses = uses(&placed.svg);
+    placed_uses.sort();
+    for seed in ["1", "2", "3", "4", "5"] {
+        for (order, darkest_first) in [("luminance", true), ("luminance-reverse", false)] {
+            let run = run(&format!("roundtrip-{order}-{seed}"), "input.png", &["-c", "64", "-s", "16", "--seed", seed, "--sort-emit", order]);
+            let mut sorted_uses = uses(&run.svg);
+            let lightnesses: Vec<f32> = sorted_uses.iter().map(|tag| lightness(tag)).collect();
+            assert!(lightnesses.is_sorted_by(|a, b| if darkest_first { a <= b } else { a >= b }), "{order} isn't sorted: {lightnesses:?}");
+            if seed == "1" {
+                sorted_uses.sort();
+                assert_eq!(sorted_uses, placed_uses, "{order} changed the shapes");
+            }
+            assert_round_trips_within(&run, "input.png", 64, Metric::Hybrid, LUMINANCE_TOLERANCE);
+        }
+    }
+}
+
+#[test]
+fn flat_input_meeting_the_score_floor_places_nothing() {
+    let run = run("flat-score-floor", "flat.png", &["-c", "24", "--score-floor", "99"]); // With the default 500 shapes
+    assert!(run.log.contains("meeting --score-floor, so no shapes will be placed"), "{}", run.log);
+    assert_eq!(run.score, None);
+    assert!(!run.svg.contains("<use"), "{}", run.svg);
+}
+
+#[test]
+fn flat_input_meeting_the_matchscore_places_nothing() {
+    let run = run("flat-matchscore", "flat.png", &["-c", "24", "-s", "0", "-m", "99"]);
+    assert!(run.log.contains("meeting --matchscore, so no shapes are needed"), "{}", run.log);
+    assert_eq!(run.score, None);
+    assert!(!run.svg.contains("<use"), "{}", run.svg);
+}
+
+/// Near a flat target mse improvements drop below the default score precision, without refining it the run stalls around 0.0015% error
+/// and spends all of --failmax there
+#[test]
+fn score_precision_is_refined_instead_of_thrashing() {
+    let run = run("gradient-mse", "gradient.png", &["-c", "24", "--metric", "mse", "-m", "0.0005", "-s", "0", "--primitives", "rect",
+        "--failmax", "50", "--seed", "5", "--max-fragment-size", "32"]);
+    assert!(run.log.contains("scores are now compared in steps of 1/10000000") || run.log.contains("Converged to the metric's precision"), "{}", run.log);
+    assert!(run.score.unwrap() <= 0.0005, "stopped at {:?}% error\n{}", run.score, run.log);
+}
+
+#[test]
+fn verify_subcommand_ignores_the_display_size() {
+    let run = run("roundtrip-display-size", "input.png", &["-c", "64", "-s", "16", "--seed", "3", "--svg-width", "200", "--svg-height", "40",
+        "--preserve-aspect-ratio", "xMidYMid slice"]);
+    assert!(run.svg.contains("preserveAspectRatio=\"xMidYMid slice\""), "{}", run.svg);
+    let output = Command::new(env!("CARGO_BIN_EXE_image_evo_filter")).current_dir(fixtures())
+        .args(["verify", "--input", "input.png", "-c", "64", "--svg"]).arg(&run.path)
+        .output().unwrap();
+    let log = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{log}");
+    let verified: f64 = log.split_once(" scores ").and_then(|(_, rest)| rest.split_once('%')).unwrap().0.parse().unwrap();
+    let internal = run.score.unwrap();
+    assert!((verified - internal).abs() <= TOLERANCE, "verify scores {verified:.04}%, the run scored {internal:.04}%");
+}
+
+#[test]
+fn verify_passes_with_the_default_tolerance() {
+    let run = run("roundtrip-verify", "input.png", &["-c", "64", "-s", "16", "--seed", "1", "--verify"]);
+    assert!(run.log.contains("Verify passed"), "{}", run.log);
+}
+
+#[test]
+fn verify_subcommand_reports_an_unreadable_input() {
+    let output = Command::new(env!("CARGO_BIN_EXE_image_evo_filter")).current_dir(fixtures())
+        .args(["verify", "--input", "missing.png", "--svg", "images/circle.svg"])
+        .output().unwrap();
+    let log = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1), "{log}{}", String::from_utf8_lossy(&output.stderr));
+    assert!(log.contains("Could not read missing.png"), "{log}");
+}